@@ -0,0 +1,306 @@
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::{Compression, GzBuilder};
+use liblzma::stream::{Check, Stream, PRESET_EXTREME};
+
+use crate::parallel_gzip::ParallelGzEncoder;
+
+/// Compression format wrapped around the tar stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    Lz4,
+    None,
+}
+
+pub const CODEC_NAMES: [&str; 6] = ["gzip", "bzip2", "xz", "zstd", "lz4", "none"];
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" | "gz" => Ok(Codec::Gzip),
+            "bzip2" | "bz2" => Ok(Codec::Bzip2),
+            "xz" => Ok(Codec::Xz),
+            "zstd" | "zst" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            "none" | "tar" => Ok(Codec::None),
+            _ => Err(format!("unknown codec '{}'", s)),
+        }
+    }
+}
+
+/// Requested compression level. Numeric levels are validated against the
+/// codec's own range when the encoder is built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Default,
+    Fastest,
+    Best,
+    Value(u32),
+    /// An xz preset with the extreme flag set, written as e.g. `9e`.
+    Extreme(u32),
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (digits, extreme) = match s.strip_suffix('e') {
+            Some(digits) => (digits, true),
+            None => (s, false),
+        };
+        let value = digits
+            .parse()
+            .map_err(|_| format!("invalid compression level '{}'", s))?;
+        Ok(if extreme { Level::Extreme(value) } else { Level::Value(value) })
+    }
+}
+
+impl Codec {
+    fn level_range(self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Codec::Gzip => Some(0..=9),
+            Codec::Bzip2 => Some(1..=9),
+            Codec::Xz => Some(0..=9),
+            Codec::Zstd => Some(1..=22),
+            Codec::Lz4 | Codec::None => None,
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Codec::Gzip => 6,
+            Codec::Bzip2 => 9,
+            Codec::Xz => 6,
+            Codec::Zstd => 3,
+            Codec::Lz4 | Codec::None => 0,
+        }
+    }
+
+    // Resolves `level` to a concrete value for this codec plus the xz extreme
+    // flag, rejecting values outside the codec's range.
+    fn resolve_level(self, level: Level) -> io::Result<(u32, bool)> {
+        let range = match self.level_range() {
+            Some(range) => range,
+            None => match level {
+                Level::Value(_) | Level::Extreme(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} has no compression levels", self.name()),
+                    ))
+                }
+                _ => return Ok((0, false)),
+            },
+        };
+        let (value, extreme) = match level {
+            Level::Default => (self.default_level(), false),
+            // Level 0 only stores for gzip, so its fastest real level is 1.
+            Level::Fastest if self == Codec::Gzip => (1, false),
+            Level::Fastest => (*range.start(), false),
+            Level::Best => (*range.end(), false),
+            Level::Value(value) => (value, false),
+            Level::Extreme(value) if self == Codec::Xz => (value, true),
+            Level::Extreme(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "extreme presets are only supported by xz",
+                ))
+            }
+        };
+        if !range.contains(&value) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} level must be between {} and {}",
+                    self.name(),
+                    range.start(),
+                    range.end()
+                ),
+            ));
+        }
+        Ok((value, extreme))
+    }
+
+    /// Checks that `level` applies to this codec, so a bad combination is
+    /// reported before any output file is created.
+    pub fn check_level(self, level: Level) -> io::Result<()> {
+        self.resolve_level(level).map(|_| ())
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Bzip2 => "bzip2",
+            Codec::Xz => "xz",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+            Codec::None => "none",
+        }
+    }
+
+    /// Identifies the codec from the first bytes of an archive. Plain tar is
+    /// recognised by the ustar magic at offset 257, or for pre-POSIX archives
+    /// by a valid header checksum.
+    pub fn detect(magic: &[u8]) -> Option<Codec> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Codec::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else if magic.starts_with(b"BZh") {
+            Some(Codec::Bzip2)
+        } else if magic.starts_with(&[0x04, 0x22, 0x4d, 0x18]) {
+            Some(Codec::Lz4)
+        } else if magic.get(257..262) == Some(b"ustar") || is_tar_header(magic) {
+            Some(Codec::None)
+        } else {
+            None
+        }
+    }
+
+    /// Builds a compressing writer. `threads` above one selects the parallel
+    /// gzip encoder; the other codecs always compress on the calling thread.
+    pub fn encoder<W: Write>(
+        self,
+        writer: W,
+        level: Level,
+        threads: usize,
+    ) -> io::Result<Encoder<W>> {
+        let (level, extreme) = self.resolve_level(level)?;
+        Ok(match self {
+            Codec::Gzip if threads > 1 => Encoder::ParallelGzip(ParallelGzEncoder::new(
+                writer,
+                Compression::new(level),
+                threads,
+            )),
+            Codec::Gzip => Encoder::Gzip(gzip_header().write(writer, Compression::new(level))),
+            Codec::Bzip2 => Encoder::Bzip2(bzip2::write::BzEncoder::new(
+                writer,
+                bzip2::Compression::new(level),
+            )),
+            Codec::Xz => {
+                let preset = if extreme { level | PRESET_EXTREME } else { level };
+                let stream = Stream::new_easy_encoder(preset, Check::Crc64)?;
+                Encoder::Xz(liblzma::write::XzEncoder::new_stream(writer, stream))
+            }
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, level as i32)?),
+            Codec::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+            Codec::None => Encoder::None(writer),
+        })
+    }
+
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Codec::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+            Codec::Xz => Box::new(liblzma::read::XzDecoder::new_multi_decoder(reader)),
+            Codec::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+            Codec::None => Box::new(reader),
+        })
+    }
+}
+
+/// The gzip header written by both gzip encoders. The fields that could
+/// differ between machines are pinned: no file name, a zero mtime and the
+/// "unknown" OS byte, so equal input gives equal output everywhere.
+pub fn gzip_header() -> GzBuilder {
+    GzBuilder::new().mtime(0).operating_system(255)
+}
+
+/// Wraps `reader` in the decoder for `codec`, or for the codec detected from
+/// its leading bytes when none is given. The peeked bytes are replayed, so the
+/// input never needs to be seekable.
+pub fn open_decoder<'a, R: Read + 'a>(
+    mut reader: R,
+    codec: Option<Codec>,
+) -> io::Result<(Codec, Box<dyn Read + 'a>)> {
+    if let Some(codec) = codec {
+        return Ok((codec, codec.decoder(reader)?));
+    }
+
+    let mut magic = Vec::with_capacity(512);
+    (&mut reader).take(512).read_to_end(&mut magic)?;
+    let codec = Codec::detect(&magic).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "unrecognized archive format")
+    })?;
+    let decoder = codec.decoder(io::Cursor::new(magic).chain(reader))?;
+    Ok((codec, decoder))
+}
+
+fn is_tar_header(block: &[u8]) -> bool {
+    if block.len() < 512 {
+        return false;
+    }
+    if block[..512].iter().all(|&b| b == 0) {
+        return true;
+    }
+    let stored = std::str::from_utf8(&block[148..156])
+        .ok()
+        .map(|s| s.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|s| u32::from_str_radix(s, 8).ok());
+    let computed: u32 = block[..512]
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u32 } else { b as u32 })
+        .sum();
+    stored == Some(computed)
+}
+
+/// A compressing writer for any [`Codec`]. Call [`Encoder::finish`] to write
+/// the trailer and get the inner writer back; dropping it may lose data.
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    ParallelGzip(ParallelGzEncoder<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Xz(liblzma::write::XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::ParallelGzip(e) => e.finish(),
+            Encoder::Bzip2(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Lz4(e) => e.finish().map_err(io::Error::other),
+            Encoder::None(w) => Ok(w),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Gzip(e) => e,
+            Encoder::ParallelGzip(e) => e,
+            Encoder::Bzip2(e) => e,
+            Encoder::Xz(e) => e,
+            Encoder::Zstd(e) => e,
+            Encoder::Lz4(e) => e,
+            Encoder::None(w) => w,
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::SigningKey;
+use rustix::fs as rfs;
+use tar::{Builder, EntryType, Header, HeaderMode};
+
+use crate::codec::{Codec, Level};
+use crate::crypt::Encryption;
+use crate::incremental::{self, Planned, Snapshot};
+use crate::owner::Owners;
+use crate::sparse::{self, RegionReader};
+use crate::transform::{self, Transform};
+use crate::verify::{self, HashingReader};
+use crate::walk::{self, Filter};
+use crate::xattrs;
+
+/// Settings shared by --compress and --add. `codec` is only set when given on
+/// the command line; --add otherwise keeps the codec of the existing archive.
+pub struct CreateOptions {
+    pub codec: Option<Codec>,
+    pub level: Level,
+    pub threads: usize,
+    pub filter: Filter,
+    pub respect_ignore: bool,
+    pub transforms: Vec<Transform>,
+    pub numeric_owner: bool,
+    pub dereference: bool,
+    pub sparse: bool,
+    /// --digest: store each regular file's SHA-256 for --verify.
+    pub digest: bool,
+    pub reproducible: bool,
+    /// `SOURCE_DATE_EPOCH`, read only with --reproducible.
+    pub source_date_epoch: Option<u64>,
+    pub listed_incremental: Option<PathBuf>,
+    pub encryption: Encryption,
+    /// The --sign key, to sign the finished archive with.
+    pub sign_key: Option<SigningKey>,
+    /// --split-size: the most bytes to write to each part of the archive.
+    pub split_size: Option<u64>,
+}
+
+/// Walks `input_files` and appends every entry to `tar` under its
+/// transformed name. With --reproducible the entries of all inputs are
+/// sorted by that name, so the archive does not depend on argument order.
+/// With a `snapshot` (--listed-incremental) only what changed since it is
+/// stored; see [`incremental::plan`]. Returns how many directories could not
+/// be read, for [`walk::check_unreadable`] once the archive is finished.
+pub fn append_inputs<W: Write>(
+    tar: &mut Builder<W>,
+    input_files: &[&str],
+    options: &CreateOptions,
+    snapshot: Option<&mut Snapshot>,
+) -> io::Result<usize> {
+    let mut appender = Appender {
+        tar,
+        options,
+        owners: Owners::new(),
+        links: HashMap::new(),
+    };
+    let walk = walk::collect(input_files, &options.filter, options.respect_ignore, options.dereference)?;
+    let mut entries = Vec::new();
+    for entry in walk.entries {
+        let name = transform::apply_all(&options.transforms, &entry.name);
+        if !name.as_os_str().is_empty() {
+            entries.push((name, entry.src));
+        }
+    }
+    if options.reproducible {
+        entries.sort();
+    }
+    let planned = match snapshot {
+        Some(snapshot) => incremental::plan(entries, snapshot, options.dereference)?,
+        None => entries
+            .into_iter()
+            .map(|(name, src)| Planned { name, src, dumpdir: None })
+            .collect(),
+    };
+    for entry in planned {
+        appender.append(&entry.src, &entry.name, entry.dumpdir.as_deref())?;
+    }
+    Ok(walk.unreadable)
+}
+
+struct Appender<'a, W: Write> {
+    tar: &'a mut Builder<W>,
+    options: &'a CreateOptions,
+    owners: Owners,
+    // Archive names of files with several links, by (device, inode), so
+    // later links are stored as hard link entries rather than again in full.
+    links: HashMap<(u64, u64), PathBuf>,
+}
+
+impl<W: Write> Appender<'_, W> {
+    // Stores `src` with all of its metadata: the full mode including the
+    // setuid, setgid and sticky bits, mtime, owner and group ids and, unless
+    // --numeric-owner, the owner and group names, which extraction prefers
+    // over the ids. Extended attributes, ACLs and the SELinux context go in a
+    // PAX header in front of the entry, along with the SHA-256 of a regular
+    // file's contents with --digest, unless it is stored sparse. With
+    // --dereference a symlink is stored as the file it
+    // points to; a dangling one is kept as a link. A directory with `dumpdir`
+    // data becomes a GNU dumpdir entry.
+    //
+    // With --reproducible, owners become 0/0 without names, modes 0644 or
+    // 0755 and mtimes are clamped to SOURCE_DATE_EPOCH (or all set to the tar
+    // crate's fixed timestamp when it is unset). The GNU header's atime and
+    // ctime fields, this format's counterpart of the PAX atime/ctime
+    // records, are left empty, and no such records are written. Xattrs, ACLs
+    // and the SELinux context are left out too, as they carry host-specific
+    // labels and user and group names.
+    fn append(&mut self, src: &Path, name: &Path, dumpdir: Option<&[u8]>) -> io::Result<()> {
+        let follow = self.options.dereference;
+        let meta = walk::metadata(src, follow)?;
+        let file_type = meta.file_type();
+        if file_type.is_socket() {
+            eprintln!("⚠️ Skipping socket: {}", src.display());
+            return Ok(());
+        }
+
+        let mut header = Header::new_gnu();
+        if self.options.reproducible {
+            header.set_metadata_in_mode(&meta, HeaderMode::Deterministic);
+            if let Some(epoch) = self.options.source_date_epoch {
+                header.set_mtime((meta.mtime().max(0) as u64).min(epoch));
+            }
+        } else {
+            header.set_metadata(&meta);
+            header.set_mode(meta.mode() & 0o7777);
+        }
+        if !self.options.numeric_owner && !self.options.reproducible {
+            // Names too long for the 32-byte header fields are left out; the
+            // ids are still stored.
+            if let Some(user) = self.owners.user_name(meta.uid()) {
+                let _ = header.set_username(&user);
+            }
+            if let Some(group) = self.owners.group_name(meta.gid()) {
+                let _ = header.set_groupname(&group);
+            }
+        }
+
+        if !file_type.is_dir() && meta.nlink() > 1 {
+            match self.links.entry((meta.dev(), meta.ino())) {
+                Entry::Occupied(first) => {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    return self.tar.append_link(&mut header, name, first.get());
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(name.to_path_buf());
+                }
+            }
+        }
+
+        let follow = follow && !file_type.is_symlink();
+        let mut records = if self.options.reproducible {
+            Vec::new()
+        } else {
+            xattrs::read(src, &self.owners, follow)?
+        };
+        let mut file = if file_type.is_file() && dumpdir.is_none() {
+            Some(File::open(src)?)
+        } else {
+            None
+        };
+        let regions = match &file {
+            Some(file) if self.options.sparse => sparse::data_regions(file, meta.len())?,
+            _ => None,
+        };
+        // With --digest, regular files get a digest of their contents for
+        // --verify, taken in a pass of its own since the PAX header has to
+        // come first. Sparse files are left without one: that pass would
+        // read through every hole.
+        let mut digest = None;
+        if let (Some(file), true, None) = (file.as_mut(), self.options.digest, &regions) {
+            let (sum, _) = verify::sha256(&*file)?;
+            file.rewind()?;
+            records.push((verify::SHA256_RECORD.to_string(), verify::to_hex(&sum).into_bytes()));
+            digest = Some(sum);
+        }
+        self.tar
+            .append_pax_extensions(records.iter().map(|(key, value)| (key.as_str(), value.as_slice())))?;
+
+        if let Some(dumpdir) = dumpdir {
+            header.set_entry_type(EntryType::new(incremental::DUMPDIR));
+            header.set_size(dumpdir.len() as u64);
+            self.tar.append_data(&mut header, name, dumpdir)
+        } else if file_type.is_symlink() {
+            self.tar.append_link(&mut header, name, fs::read_link(src)?)
+        } else if let Some(file) = file {
+            if let Some(regions) = regions {
+                // The extension headers go out as the start of the entry's
+                // data, ahead of the data regions, as the format wants.
+                let extensions = sparse::prepare_header(&mut header, &regions, meta.len());
+                let data = io::Cursor::new(extensions).chain(RegionReader::new(&file, regions));
+                return self.tar.append_data(&mut header, name, data);
+            }
+            let Some(digest) = digest else {
+                return self.tar.append_data(&mut header, name, &file);
+            };
+            let mut data = HashingReader::new(&file);
+            self.tar.append_data(&mut header, name, &mut data)?;
+            if data.finish() != digest {
+                eprintln!("⚠️ {} changed while being archived; its digest will not verify", src.display());
+            }
+            Ok(())
+        } else {
+            if file_type.is_char_device() || file_type.is_block_device() {
+                header.set_device_major(rfs::major(meta.rdev()))?;
+                header.set_device_minor(rfs::minor(meta.rdev()))?;
+            }
+            self.tar.append_data(&mut header, name, io::empty())
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tar::Archive;
+
+    /// Creation settings with no flags given, bar --numeric-owner so the
+    /// archives do not depend on the user and group database.
+    pub fn create_options() -> CreateOptions {
+        CreateOptions {
+            codec: None,
+            level: Level::Default,
+            threads: 1,
+            filter: Filter::new(&[], &[]).unwrap(),
+            respect_ignore: false,
+            transforms: Vec::new(),
+            numeric_owner: true,
+            dereference: false,
+            sparse: false,
+            digest: false,
+            reproducible: false,
+            source_date_epoch: None,
+            listed_incremental: None,
+            encryption: Encryption::None,
+            sign_key: None,
+            split_size: None,
+        }
+    }
+
+    fn pax_keys(src: &Path, options: &CreateOptions) -> Vec<String> {
+        let mut tar = Builder::new(Vec::new());
+        append_inputs(&mut tar, &[src.to_str().unwrap()], options, None).unwrap();
+        let archive = tar.into_inner().unwrap();
+        let mut archive = Archive::new(archive.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let Some(extensions) = entry.pax_extensions().unwrap() else {
+            return Vec::new();
+        };
+        extensions.map(|record| record.unwrap().key().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn digests_are_only_stored_with_the_option() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("file.txt");
+        fs::write(&src, "contents").unwrap();
+        let sha256 = verify::SHA256_RECORD.to_string();
+
+        assert!(!pax_keys(&src, &create_options()).contains(&sha256));
+        let digest = CreateOptions { digest: true, ..create_options() };
+        assert!(pax_keys(&src, &digest).contains(&sha256));
+
+        // A file stored sparse is not read through its holes for one.
+        let file = File::create(&src).unwrap();
+        file.set_len(1 << 20).unwrap();
+        if sparse::data_regions(&file, 1 << 20).unwrap().is_some() {
+            let sparse = CreateOptions { sparse: true, ..digest };
+            assert!(!pax_keys(&src, &sparse).contains(&sha256));
+        }
+    }
+
+    #[test]
+    fn reproducible_archives_leave_out_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("file.txt");
+        fs::write(&src, "contents").unwrap();
+        if rfs::setxattr(&src, "user.tart.test", b"value", rfs::XattrFlags::empty()).is_err() {
+            return; // No user xattrs on this file system.
+        }
+
+        let keys = pax_keys(&src, &create_options());
+        assert!(keys.contains(&"SCHILY.xattr.user.tart.test".to_string()), "{:?}", keys);
+
+        let reproducible = CreateOptions { reproducible: true, digest: true, ..create_options() };
+        assert_eq!(pax_keys(&src, &reproducible), [verify::SHA256_RECORD]);
+    }
+}
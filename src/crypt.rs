@@ -0,0 +1,160 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use age::secrecy::SecretString;
+use age::stream::StreamWriter;
+use age::{DecryptError, Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+
+/// The first line of every binary age file.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+/// How created archives are encrypted. Recipients win over a passphrase when
+/// both are given, as age cannot mix the two in one file; the passphrase is
+/// then only used to read.
+pub enum Encryption {
+    None,
+    Passphrase(SecretString),
+    Recipients(Vec<age::x25519::Recipient>),
+}
+
+/// What encrypted archives can be read with: the --passphrase-file
+/// passphrase and the --identity keys.
+pub struct Keys {
+    pub passphrase: Option<SecretString>,
+    pub identities: Vec<Box<dyn Identity>>,
+}
+
+/// Reads a passphrase from the first line of `path`. Keeping it in a file
+/// (or a pipe such as `<(pass show backup)`) keeps it out of `ps`.
+pub fn read_passphrase(path: &Path) -> io::Result<SecretString> {
+    let data = fs::read_to_string(path)?;
+    let line = data.lines().next().unwrap_or("");
+    if line.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not start with a passphrase", path.display()),
+        ));
+    }
+    Ok(SecretString::from(line.to_string()))
+}
+
+/// Reads the X25519 secret keys of an age identity file, as written by
+/// `age-keygen`.
+pub fn read_identities(path: &Path) -> io::Result<Vec<Box<dyn Identity>>> {
+    IdentityFile::from_file(path.to_string_lossy().into_owned())?
+        .into_identities()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+/// A writer that encrypts what it is given, or passes it through unchanged
+/// for [`Encryption::None`]. Call [`EncryptWriter::finish`] to write the
+/// last chunk; dropping it leaves a truncated file that does not decrypt.
+pub enum EncryptWriter<W: Write> {
+    Plain(W),
+    Age(StreamWriter<W>),
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, encryption: &Encryption) -> io::Result<EncryptWriter<W>> {
+        let encryptor = match encryption {
+            Encryption::None => return Ok(EncryptWriter::Plain(inner)),
+            Encryption::Passphrase(passphrase) => Encryptor::with_user_passphrase(passphrase.clone()),
+            Encryption::Recipients(recipients) => {
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn Recipient))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?
+            }
+        };
+        Ok(EncryptWriter::Age(encryptor.wrap_output(inner)?))
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            EncryptWriter::Plain(inner) => Ok(inner),
+            EncryptWriter::Age(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            EncryptWriter::Plain(inner) => inner.write(buf),
+            EncryptWriter::Age(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            EncryptWriter::Plain(inner) => inner.flush(),
+            EncryptWriter::Age(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Decrypts `reader` if it starts with an age header, and returns it as is
+/// otherwise, replaying the peeked bytes. Whether the file was encrypted
+/// comes back alongside.
+///
+/// age authenticates the payload in 64 KiB chunks and never releases the
+/// plaintext of a chunk that fails its check, so tampering surfaces as a
+/// read error at the damaged chunk rather than as altered data.
+pub fn decrypt<'a, R: Read + 'a>(mut reader: R, keys: &Keys) -> io::Result<(bool, Box<dyn Read + 'a>)> {
+    let mut magic = Vec::with_capacity(AGE_MAGIC.len());
+    (&mut reader).take(AGE_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    let reader = io::Cursor::new(magic).chain(reader);
+    if reader.get_ref().0.get_ref() != AGE_MAGIC {
+        return Ok((false, Box::new(reader)));
+    }
+
+    let decryptor = Decryptor::new(reader).map_err(decrypt_error)?;
+    let scrypt;
+    let identities: Vec<&dyn Identity> = if decryptor.is_scrypt() {
+        let passphrase = keys.passphrase.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the archive is encrypted with a passphrase; give --passphrase-file",
+            )
+        })?;
+        scrypt = age::scrypt::Identity::new(passphrase);
+        vec![&scrypt]
+    } else {
+        if keys.identities.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the archive is encrypted to public keys; give --identity",
+            ));
+        }
+        keys.identities.iter().map(|identity| identity.as_ref()).collect()
+    };
+    let stream = decryptor.decrypt(identities.into_iter()).map_err(decrypt_error)?;
+    Ok((true, Box::new(Authenticated(stream))))
+}
+
+// Rewords the stream's terse errors for chunks that fail authentication,
+// which is also how truncation shows up.
+struct Authenticated<R>(R);
+
+impl<R: Read> Read for Authenticated<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted data is damaged, truncated or was tampered with",
+            ),
+            _ => e,
+        })
+    }
+}
+
+fn decrypt_error(e: DecryptError) -> io::Error {
+    let message = match e {
+        DecryptError::Io(e) => return e,
+        DecryptError::NoMatchingKeys | DecryptError::DecryptionFailed => {
+            "wrong passphrase or key, or the archive header is damaged".to_string()
+        }
+        DecryptError::InvalidMac | DecryptError::InvalidHeader => "the archive's encryption header is damaged".to_string(),
+        e => e.to_string(),
+    };
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,477 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use rustix::fs::{self as rfs, AtFlags, FileType, Gid, Mode, OFlags, Timespec, Timestamps, Uid};
+use rustix::io::Errno;
+use rustix::process;
+
+/// Why hardened extraction refused an entry. It travels inside an
+/// `io::Error` so the unpacking code can use `?` throughout; see
+/// [`as_rejection`].
+#[derive(Debug)]
+pub struct Rejected(String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Rejected {}
+
+pub fn rejected(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Rejected(reason))
+}
+
+pub fn as_rejection(e: &io::Error) -> Option<&Rejected> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<Rejected>())
+}
+
+/// Validates an entry or hard link name and returns it relative to the
+/// destination. Absolute names and `..` components are rejected; with
+/// `unsafe_paths` the leading `/` is dropped and `..` is kept instead.
+pub fn check_name(path: &Path, unsafe_paths: bool) -> io::Result<PathBuf> {
+    let mut rel = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Prefix(_) | Component::RootDir if unsafe_paths => {}
+            Component::Prefix(_) | Component::RootDir => {
+                return Err(rejected(format!("absolute path {}", path.display())))
+            }
+            Component::ParentDir if unsafe_paths => rel.push(".."),
+            Component::ParentDir => {
+                return Err(rejected(format!("path {} contains '..'", path.display())))
+            }
+            Component::Normal(part) => rel.push(part),
+        }
+    }
+    Ok(rel)
+}
+
+// A symlink at `rel` may only point to a relative target that stays below
+// the destination. Leading `..` components may climb no higher than the
+// destination root, and `..` after a directory name is refused outright,
+// because that directory could itself be a symlink and the kernel resolves
+// `dir/..` through it rather than lexically.
+fn check_symlink_target(rel: &Path, target: &Path) -> io::Result<()> {
+    let mut depth = rel.components().count() as isize - 1;
+    let mut seen_name = false;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Prefix(_) | Component::RootDir => {
+                return Err(rejected(format!("symlink target {} is absolute", target.display())))
+            }
+            Component::ParentDir if seen_name => {
+                return Err(rejected(format!(
+                    "symlink target {} has '..' after a directory name",
+                    target.display()
+                )))
+            }
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return Err(rejected(format!(
+                        "symlink target {} points outside the destination",
+                        target.display()
+                    )));
+                }
+            }
+            Component::Normal(_) => {
+                seen_name = true;
+                depth += 1;
+            }
+        }
+    }
+    if target.as_os_str().is_empty() {
+        return Err(rejected("empty symlink target".to_string()));
+    }
+    Ok(())
+}
+
+/// The directory being extracted into. Every operation starts from an open
+/// handle on it and walks the entry's parent directories one `openat` at a
+/// time with `O_NOFOLLOW`, so an entry can never be written through a
+/// symlink, whether it came from the archive or was already on disk. The
+/// final component is always unlinked and recreated rather than opened, so
+/// it is never followed either.
+///
+/// With `unsafe_paths` the parent directories are resolved by path instead,
+/// following symlinks, and link targets are not checked.
+pub struct Destination {
+    root: OwnedFd,
+    path: PathBuf,
+    unsafe_paths: bool,
+}
+
+impl Destination {
+    pub fn open(path: &Path, unsafe_paths: bool) -> io::Result<Destination> {
+        fs::create_dir_all(path)?;
+        let root = rfs::open(path, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())?;
+        Ok(Destination {
+            root,
+            path: path.to_path_buf(),
+            unsafe_paths,
+        })
+    }
+
+    /// Opens the directory containing `rel`, creating missing directories
+    /// when `create` is set, and returns it with the final component.
+    fn parent<'a>(&self, rel: &'a Path, create: bool) -> io::Result<(OwnedFd, &'a OsStr)> {
+        let name = rel.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name {}", rel.display()))
+        })?;
+        let parent = rel.parent().unwrap_or(Path::new(""));
+
+        if self.unsafe_paths {
+            let dir = self.path.join(parent);
+            if create {
+                fs::create_dir_all(&dir)?;
+            }
+            let fd = rfs::open(&dir, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())?;
+            return Ok((fd, name));
+        }
+
+        let mut dir = self.root.try_clone()?;
+        let mut walked = PathBuf::new();
+        for component in parent.components() {
+            walked.push(component);
+            dir = self.open_subdir(&dir, component.as_os_str(), &walked, create)?;
+        }
+        Ok((dir, name))
+    }
+
+    fn open_subdir(&self, dir: &OwnedFd, name: &OsStr, walked: &Path, create: bool) -> io::Result<OwnedFd> {
+        let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+        match rfs::openat(dir, name, flags, Mode::empty()) {
+            Ok(fd) => Ok(fd),
+            Err(Errno::NOENT) if create => {
+                match rfs::mkdirat(dir, name, Mode::from_raw_mode(0o777)) {
+                    Ok(()) | Err(Errno::EXIST) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                Ok(rfs::openat(dir, name, flags, Mode::empty())?)
+            }
+            Err(Errno::LOOP) | Err(Errno::NOTDIR) if self.is_symlink(dir, name) => Err(rejected(format!(
+                "{} is a symlink and would be written through",
+                walked.display()
+            ))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn is_symlink(&self, dir: &OwnedFd, name: &OsStr) -> bool {
+        rfs::statat(dir, name, AtFlags::SYMLINK_NOFOLLOW)
+            .is_ok_and(|st| FileType::from_raw_mode(st.st_mode) == FileType::Symlink)
+    }
+
+    /// Creates an empty regular file at `rel`, replacing whatever was there.
+    pub fn create_file(&self, rel: &Path) -> io::Result<File> {
+        let (dir, name) = self.parent(rel, true)?;
+        remove_existing(&dir, name, rel)?;
+        let flags = OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+        let fd = rfs::openat(&dir, name, flags, Mode::from_raw_mode(0o600))?;
+        Ok(File::from(fd))
+    }
+
+    /// Creates the directory `rel` if it does not exist yet. A symlink or
+    /// file in its place is replaced rather than followed.
+    pub fn create_dir(&self, rel: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(rel, true)?;
+        match rfs::statat(&dir, name, AtFlags::SYMLINK_NOFOLLOW) {
+            Ok(st) if FileType::from_raw_mode(st.st_mode) == FileType::Directory => return Ok(()),
+            Ok(st) if self.unsafe_paths && FileType::from_raw_mode(st.st_mode) == FileType::Symlink => {
+                return Ok(())
+            }
+            Ok(_) => remove_existing(&dir, name, rel)?,
+            Err(Errno::NOENT) => {}
+            Err(e) => return Err(e.into()),
+        }
+        rfs::mkdirat(&dir, name, Mode::from_raw_mode(0o700))?;
+        Ok(())
+    }
+
+    /// Opens the existing directory `rel` without following symlinks.
+    pub fn open_dir(&self, rel: &Path) -> io::Result<File> {
+        let (dir, name) = self.parent(rel, false)?;
+        let fd = self.open_subdir(&dir, name, rel, false)?;
+        Ok(File::from(fd))
+    }
+
+    pub fn symlink(&self, rel: &Path, target: &Path) -> io::Result<()> {
+        if !self.unsafe_paths {
+            check_symlink_target(rel, target)?;
+        }
+        let (dir, name) = self.parent(rel, true)?;
+        remove_existing(&dir, name, rel)?;
+        rfs::symlinkat(target, &dir, name)?;
+        Ok(())
+    }
+
+    /// Hard-links `rel` to the already extracted `source`. The source is
+    /// resolved like any other entry, so it is always inside the destination.
+    pub fn hard_link(&self, rel: &Path, source: &Path) -> io::Result<()> {
+        let (source_dir, source_name) = self.parent(source, false)?;
+        let (dir, name) = self.parent(rel, true)?;
+        remove_existing(&dir, name, rel)?;
+        rfs::linkat(&source_dir, source_name, &dir, name, AtFlags::empty())?;
+        Ok(())
+    }
+
+    /// Creates a FIFO or a character or block device at `rel`, replacing
+    /// whatever was there. It starts out private, like [`create_file`], until
+    /// [`set_mode_at`] applies the archived mode.
+    ///
+    /// [`create_file`]: Destination::create_file
+    /// [`set_mode_at`]: Destination::set_mode_at
+    pub fn mknod(&self, rel: &Path, file_type: FileType, dev: u64) -> io::Result<()> {
+        let (dir, name) = self.parent(rel, true)?;
+        remove_existing(&dir, name, rel)?;
+        rfs::mknodat(&dir, name, file_type, Mode::from_raw_mode(0o600), dev)?;
+        Ok(())
+    }
+
+    /// Deletes everything in the directory `rel` whose name is not in
+    /// `keep`, descending into subdirectories without following symlinks.
+    pub fn remove_unlisted(&self, rel: &Path, keep: &HashSet<OsString>) -> io::Result<()> {
+        let dir = self.open_dir(rel)?;
+        for name in dir_names(&dir)? {
+            if !keep.contains(&name) {
+                remove_tree(&dir, &name)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Symlinks, FIFOs and device nodes are never opened, since opening a
+    // FIFO blocks and opening a device can have side effects; their metadata
+    // is set by name instead, without following a symlink.
+
+    pub fn set_times_at(&self, rel: &Path, mtime: u64) -> io::Result<()> {
+        let (dir, name) = self.parent(rel, false)?;
+        rfs::utimensat(&dir, name, &timestamps(mtime), AtFlags::SYMLINK_NOFOLLOW)?;
+        Ok(())
+    }
+
+    pub fn set_owner_at(&self, rel: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        let (dir, name) = self.parent(rel, false)?;
+        rfs::chownat(&dir, name, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)), AtFlags::SYMLINK_NOFOLLOW)?;
+        Ok(())
+    }
+
+    /// Linux cannot change the mode of a symlink, so this is not for them.
+    /// The entry is opened with `O_PATH`, which neither blocks on a FIFO
+    /// nor touches a device, and `O_NOFOLLOW`, so a symlink put in its place
+    /// is found rather than followed. `fchmod` refuses `O_PATH` descriptors,
+    /// so the mode is set through the descriptor's `/proc/self/fd` link,
+    /// which names the opened inode itself.
+    pub fn set_mode_at(&self, rel: &Path, mode: u32) -> io::Result<()> {
+        let (dir, name) = self.parent(rel, false)?;
+        let flags = OFlags::PATH | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+        let fd = rfs::openat(&dir, name, flags, Mode::empty())?;
+        if FileType::from_raw_mode(rfs::fstat(&fd)?.st_mode) == FileType::Symlink {
+            return Err(Errno::LOOP.into());
+        }
+        rfs::chmod(format!("/proc/self/fd/{}", fd.as_raw_fd()), Mode::from_raw_mode(mode))?;
+        Ok(())
+    }
+}
+
+pub fn set_times(file: &File, mtime: u64) -> io::Result<()> {
+    rfs::futimens(file, &timestamps(mtime))?;
+    Ok(())
+}
+
+pub fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+    rfs::fchmod(file, Mode::from_raw_mode(mode))?;
+    Ok(())
+}
+
+/// Changes the owner and group of `file`. The kernel clears the setuid and
+/// setgid bits on a chown, so this has to come before [`set_mode`].
+pub fn set_owner(file: &File, uid: u32, gid: u32) -> io::Result<()> {
+    rfs::fchown(file, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+    Ok(())
+}
+
+pub fn is_root() -> bool {
+    process::geteuid().is_root()
+}
+
+/// The process umask. Reading it means setting it, so it is put straight back.
+pub fn current_umask() -> u32 {
+    let mask = process::umask(Mode::empty());
+    process::umask(mask);
+    mask.bits()
+}
+
+pub fn set_umask(mask: u32) {
+    process::umask(Mode::from_raw_mode(mask));
+}
+
+fn timestamps(mtime: u64) -> Timestamps {
+    let time = Timespec {
+        tv_sec: mtime as _,
+        tv_nsec: 0,
+    };
+    Timestamps {
+        last_access: time,
+        last_modification: time,
+    }
+}
+
+// Unlinks a non-directory, or an empty directory, so the entry can be
+// created fresh in its place.
+fn remove_existing(dir: &OwnedFd, name: &OsStr, rel: &Path) -> io::Result<()> {
+    match rfs::unlinkat(dir, name, AtFlags::empty()) {
+        Ok(()) | Err(Errno::NOENT) => Ok(()),
+        Err(Errno::ISDIR) | Err(Errno::PERM) => match rfs::unlinkat(dir, name, AtFlags::REMOVEDIR) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(io::Error::new(
+                io::Error::from(e).kind(),
+                format!("cannot replace directory {}", rel.display()),
+            )),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn dir_names(dir: impl AsFd) -> io::Result<Vec<OsString>> {
+    let mut names = Vec::new();
+    for entry in rfs::Dir::read_from(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsStr::from_bytes(name).to_os_string());
+        }
+    }
+    Ok(names)
+}
+
+// Unlinks `name` in `dir`, emptying it first if it is a directory.
+fn remove_tree(dir: impl AsFd, name: &OsStr) -> io::Result<()> {
+    match rfs::unlinkat(&dir, name, AtFlags::empty()) {
+        Ok(()) | Err(Errno::NOENT) => Ok(()),
+        Err(Errno::ISDIR) | Err(Errno::PERM) => {
+            let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+            let sub = rfs::openat(&dir, name, flags, Mode::empty())?;
+            for child in dir_names(&sub)? {
+                remove_tree(&sub, &child)?;
+            }
+            rfs::unlinkat(&dir, name, AtFlags::REMOVEDIR)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+    fn is_rejected(result: io::Result<impl fmt::Debug>) -> bool {
+        result.as_ref().err().and_then(as_rejection).is_some()
+    }
+
+    #[test]
+    fn check_name_rejects_absolute_and_parent_components() {
+        assert_eq!(check_name(Path::new("./a/./b"), false).unwrap(), Path::new("a/b"));
+        assert!(is_rejected(check_name(Path::new("/etc/passwd"), false)));
+        assert!(is_rejected(check_name(Path::new("a/../../x"), false)));
+        assert!(is_rejected(check_name(Path::new("a/.."), false)));
+    }
+
+    #[test]
+    fn check_name_with_unsafe_paths_keeps_parents_and_drops_the_root() {
+        assert_eq!(check_name(Path::new("/etc/passwd"), true).unwrap(), Path::new("etc/passwd"));
+        assert_eq!(check_name(Path::new("../x"), true).unwrap(), Path::new("../x"));
+    }
+
+    #[test]
+    fn symlink_targets_must_stay_below_the_destination() {
+        check_symlink_target(Path::new("link"), Path::new("sub/file")).unwrap();
+        check_symlink_target(Path::new("a/link"), Path::new("../b")).unwrap();
+        check_symlink_target(Path::new("a/b/link"), Path::new("./../../c")).unwrap();
+        assert!(is_rejected(check_symlink_target(Path::new("link"), Path::new("../b"))));
+        assert!(is_rejected(check_symlink_target(Path::new("a/link"), Path::new("../../b"))));
+        assert!(is_rejected(check_symlink_target(Path::new("link"), Path::new("/etc"))));
+        assert!(is_rejected(check_symlink_target(Path::new("a/link"), Path::new("x/../../b"))));
+        assert!(is_rejected(check_symlink_target(Path::new("link"), Path::new(""))));
+    }
+
+    #[test]
+    fn nothing_is_written_through_a_symlinked_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("secret"), "secret").unwrap();
+        let root = tmp.path().join("dest");
+        let dest = Destination::open(&root, false).unwrap();
+        symlink(&outside, root.join("evil")).unwrap();
+
+        assert!(is_rejected(dest.create_file(Path::new("evil/new"))));
+        assert!(is_rejected(dest.create_dir(Path::new("evil/dir"))));
+        assert!(is_rejected(dest.symlink(Path::new("evil/link"), Path::new("x"))));
+        assert!(is_rejected(dest.hard_link(Path::new("copy"), Path::new("evil/secret"))));
+        assert!(is_rejected(dest.open_dir(Path::new("evil"))));
+        assert!(!outside.join("new").exists() && !outside.join("dir").exists());
+        assert!(!root.join("copy").exists());
+    }
+
+    #[test]
+    fn a_symlink_in_place_of_an_entry_is_replaced_not_followed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let victim = tmp.path().join("victim");
+        fs::write(&victim, "original").unwrap();
+        let root = tmp.path().join("dest");
+        let dest = Destination::open(&root, false).unwrap();
+        symlink(&victim, root.join("file")).unwrap();
+
+        let mut file = dest.create_file(Path::new("file")).unwrap();
+        io::Write::write_all(&mut file, b"extracted").unwrap();
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "original");
+        assert!(!fs::symlink_metadata(root.join("file")).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn unsafe_paths_follow_symlinked_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let root = tmp.path().join("dest");
+        let dest = Destination::open(&root, true).unwrap();
+        symlink(&outside, root.join("trusted")).unwrap();
+
+        dest.create_file(Path::new("trusted/new")).unwrap();
+        assert!(outside.join("new").exists());
+    }
+
+    #[test]
+    fn modes_are_not_set_through_a_symlink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let victim = tmp.path().join("victim");
+        fs::write(&victim, "original").unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o600)).unwrap();
+        let root = tmp.path().join("dest");
+        let dest = Destination::open(&root, false).unwrap();
+
+        dest.mknod(Path::new("fifo"), FileType::Fifo, 0).unwrap();
+        dest.set_mode_at(Path::new("fifo"), 0o640).unwrap();
+        assert_eq!(fs::symlink_metadata(root.join("fifo")).unwrap().mode() & 0o7777, 0o640);
+
+        // As if swapped in between creating the entry and setting its mode.
+        fs::remove_file(root.join("fifo")).unwrap();
+        symlink(&victim, root.join("fifo")).unwrap();
+        assert!(dest.set_mode_at(Path::new("fifo"), 0o4777).is_err());
+        assert_eq!(fs::metadata(&victim).unwrap().mode() & 0o7777, 0o600);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use rustix::fs as rfs;
+use tar::{Archive, Entry};
+
+use crate::dest;
+use crate::extract::{self, ExtractOptions, Selection};
+use crate::owner::Owners;
+use crate::verify::sha256;
+
+/// Compares the selected entries of the archive read from `reader` with
+/// the files below `base`, mapping names the way extraction does, and
+/// prints a line for every difference. Returns how many entries differ.
+pub fn diff<R: Read>(
+    reader: R,
+    base: &Path,
+    selection: &mut Selection,
+    options: &ExtractOptions,
+) -> io::Result<usize> {
+    let owners = Owners::new();
+    let mut archive = Archive::new(reader);
+    let mut out = io::stdout().lock();
+    let mut drifted = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = extract::normalize(&entry.path()?);
+        let is_dir = extract::is_dir(entry.header().entry_type());
+        if !extract::is_selected(&path, is_dir, selection, &options.filter) {
+            continue;
+        }
+        let rel = match options.destination(&path) {
+            Ok(Some(rel)) => rel,
+            Ok(None) => continue,
+            Err(e) => match dest::as_rejection(&e) {
+                Some(reason) => {
+                    eprintln!("⚠️ Skipping {}: {}", path.display(), reason);
+                    continue;
+                }
+                None => return Err(e),
+            },
+        };
+        let differences = compare(&mut entry, base, &rel, options, &owners)?;
+        for difference in &differences {
+            writeln!(out, "{}: {}", rel.display(), difference)?;
+        }
+        if !differences.is_empty() {
+            drifted += 1;
+        }
+    }
+    Ok(drifted)
+}
+
+// Lists how the file at `base/rel` differs from `entry`, in the words GNU
+// tar's --diff uses. Contents are compared by SHA-256.
+fn compare<R: Read>(
+    entry: &mut Entry<R>,
+    base: &Path,
+    rel: &Path,
+    options: &ExtractOptions,
+    owners: &Owners,
+) -> io::Result<Vec<String>> {
+    let disk = base.join(rel);
+    let meta = match fs::symlink_metadata(&disk) {
+        Ok(meta) => meta,
+        Err(e) => return Ok(vec![format!("Cannot stat: {}", e)]),
+    };
+    let header = entry.header().clone();
+    let entry_type = header.entry_type();
+
+    if entry_type.is_hard_link() {
+        let target = entry.link_name()?.unwrap_or_default();
+        let linked = match options.destination(&target) {
+            Ok(Some(target_rel)) => fs::symlink_metadata(base.join(target_rel))
+                .is_ok_and(|target| (target.dev(), target.ino()) == (meta.dev(), meta.ino())),
+            _ => false,
+        };
+        if linked {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![format!("Not linked to {}", target.display())]);
+    }
+
+    let file_type = meta.file_type();
+    let same_type = if extract::is_dir(entry_type) {
+        file_type.is_dir()
+    } else if entry_type.is_symlink() {
+        file_type.is_symlink()
+    } else if entry_type.is_fifo() {
+        file_type.is_fifo()
+    } else if entry_type.is_character_special() {
+        file_type.is_char_device()
+    } else if entry_type.is_block_special() {
+        file_type.is_block_device()
+    } else {
+        file_type.is_file()
+    };
+    if !same_type {
+        return Ok(vec!["File type differs".to_string()]);
+    }
+
+    let mut differences = Vec::new();
+    let mode = header.mode()? & 0o7777;
+    if !entry_type.is_symlink() && mode != meta.mode() & 0o7777 {
+        differences.push(format!("Mode differs ({:04o} in archive, {:04o} on disk)", mode, meta.mode() & 0o7777));
+    }
+    let (uid, gid) = extract::archived_owner(&header, owners, options.numeric_owner)?;
+    if uid != meta.uid() {
+        differences.push(format!("Uid differs ({} in archive, {} on disk)", uid, meta.uid()));
+    }
+    if gid != meta.gid() {
+        differences.push(format!("Gid differs ({} in archive, {} on disk)", gid, meta.gid()));
+    }
+    if header.mtime()? as i64 != meta.mtime() {
+        differences.push("Mod time differs".to_string());
+    }
+
+    if entry_type.is_symlink() {
+        let target = entry.link_name()?.unwrap_or_default();
+        let on_disk = fs::read_link(&disk)?;
+        if target != on_disk {
+            differences.push(format!(
+                "Symlink differs ({} in archive, {} on disk)",
+                target.display(),
+                on_disk.display()
+            ));
+        }
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        let archived = (header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0));
+        if archived != (rfs::major(meta.rdev()), rfs::minor(meta.rdev())) {
+            differences.push("Device number differs".to_string());
+        }
+    } else if file_type.is_file() {
+        differences.extend(compare_contents(entry, &disk, &meta)?);
+    }
+    Ok(differences)
+}
+
+fn compare_contents<R: Read>(entry: &mut Entry<R>, disk: &Path, meta: &Metadata) -> io::Result<Option<String>> {
+    let archived = sha256(entry)?;
+    let on_disk = match File::open(disk).and_then(sha256) {
+        Ok(digest) => digest,
+        Err(e) => return Ok(Some(format!("Cannot read: {}", e))),
+    };
+    if archived.0 != on_disk.0 {
+        return Ok(Some(format!("Contents differ ({} bytes in archive, {} on disk)", archived.1, meta.len())));
+    }
+    Ok(None)
+}
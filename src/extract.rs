@@ -0,0 +1,583 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use ed25519_dalek::VerifyingKey;
+use globset::{Glob, GlobMatcher};
+use rustix::fs::{self as rfs, FileType};
+use tar::{Archive, Entry, EntryType, Header};
+
+use crate::codec::Codec;
+use crate::crypt::Keys;
+use crate::dest::{self, Destination};
+use crate::incremental;
+use crate::limits::{CountingWriter, Limits, Usage};
+use crate::owner::Owners;
+use crate::pax::{PaxRecords, PaxTap};
+use crate::sparse::{HoleWriter, PaxSparse, PaxSparseReader};
+use crate::transform::{self, Transform};
+use crate::walk::Filter;
+use crate::xattrs::{self, Restore, Xattr};
+
+pub struct ExtractOptions {
+    pub codec: Option<Codec>,
+    pub filter: Filter,
+    pub strip_components: usize,
+    pub transforms: Vec<Transform>,
+    pub unsafe_paths: bool,
+    pub limits: Limits,
+    pub preserve_permissions: bool,
+    pub same_owner: bool,
+    pub numeric_owner: bool,
+    pub umask: u32,
+    pub attrs: Restore,
+    pub incremental: bool,
+    pub keys: Keys,
+    /// The --verify-signature key archives must be signed with.
+    pub trusted_key: Option<VerifyingKey>,
+}
+
+impl ExtractOptions {
+    /// Maps an archive path to where it is written below the destination:
+    /// `--transform`, then the path checks, then `--strip-components`.
+    /// `None` means nothing is left of the name and the entry is skipped.
+    pub fn destination(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        let path = transform::apply_all(&self.transforms, path);
+        let rel = dest::check_name(&path, self.unsafe_paths)?;
+        let rest: PathBuf = rel.components().skip(self.strip_components).collect();
+        if rest.as_os_str().is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(rest))
+        }
+    }
+
+    /// The mode to give an extracted entry. With --preserve-permissions it
+    /// is the archived mode including the setuid, setgid and sticky bits;
+    /// otherwise those bits are dropped and the umask applies, as in GNU tar.
+    fn mode(&self, header: &Header) -> io::Result<u32> {
+        let mode = header.mode()?;
+        if self.preserve_permissions {
+            Ok(mode & 0o7777)
+        } else {
+            Ok(mode & 0o777 & !self.umask)
+        }
+    }
+
+    /// The owner and group to give an extracted entry, or `None` without
+    /// --same-owner; see [`archived_owner`].
+    fn owner(&self, header: &Header, owners: &Owners) -> io::Result<Option<(u32, u32)>> {
+        if !self.same_owner {
+            return Ok(None);
+        }
+        archived_owner(header, owners, self.numeric_owner).map(Some)
+    }
+}
+
+/// The owner and group ids an entry stands for on this system: the archived
+/// names when they exist here, unless `numeric_owner`, otherwise the ids.
+pub fn archived_owner(header: &Header, owners: &Owners, numeric_owner: bool) -> io::Result<(u32, u32)> {
+    let mut uid = header.uid()? as u32;
+    let mut gid = header.gid()? as u32;
+    if !numeric_owner {
+        if let Some(id) = header.username().ok().flatten().and_then(|name| owners.uid(name)) {
+            uid = id;
+        }
+        if let Some(id) = header.groupname().ok().flatten().and_then(|name| owners.gid(name)) {
+            gid = id;
+        }
+    }
+    Ok((uid, gid))
+}
+
+/// Member names and wildcard patterns given after the archive name. A member
+/// selects the entry with that exact path and everything below it; arguments
+/// containing `*`, `?` or `[` are globs matched against the whole path, with
+/// `*` also matching `/` as in GNU tar's `--wildcards`.
+pub struct Selection {
+    members: Vec<Member>,
+}
+
+struct Member {
+    arg: String,
+    path: PathBuf,
+    glob: Option<GlobMatcher>,
+    found: bool,
+}
+
+impl Selection {
+    pub fn new(args: &[&str]) -> io::Result<Selection> {
+        let members = args
+            .iter()
+            .map(|arg| {
+                let glob = if arg.contains(['*', '?', '[']) {
+                    let glob = Glob::new(arg.trim_end_matches('/'))
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                    Some(glob.compile_matcher())
+                } else {
+                    None
+                };
+                Ok(Member {
+                    arg: arg.to_string(),
+                    path: normalize(Path::new(arg)),
+                    glob,
+                    found: false,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Selection { members })
+    }
+
+    /// Whether `path` was asked for, recording which members matched it.
+    pub fn matches(&mut self, path: &Path) -> bool {
+        if self.members.is_empty() {
+            return true;
+        }
+        let mut selected = false;
+        for member in &mut self.members {
+            let hit = path.ancestors().any(|ancestor| match &member.glob {
+                Some(glob) => glob.is_match(ancestor),
+                None => ancestor == member.path,
+            });
+            if hit {
+                member.found = true;
+                selected = true;
+            }
+        }
+        selected
+    }
+
+    /// Fails with every member that matched nothing, like GNU tar's
+    /// "Not found in archive".
+    pub fn check_all_found(&self) -> io::Result<()> {
+        let missing: Vec<&str> = self
+            .members
+            .iter()
+            .filter(|m| !m.found)
+            .map(|m| m.arg.as_str())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        for arg in &missing {
+            eprintln!("❌ {}: Not found in archive", arg);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("not found in archive: {}", missing.join(", ")),
+        ))
+    }
+}
+
+/// Whether the entry at `path` passes the member selection and `--exclude`.
+/// An excluded directory excludes everything below it.
+pub fn is_selected(path: &Path, is_dir: bool, selection: &mut Selection, filter: &Filter) -> bool {
+    let excluded = path
+        .ancestors()
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|a| filter.is_excluded(a, is_dir || a != path));
+    !excluded && selection.matches(path)
+}
+
+/// Whether an entry is a directory, counting GNU dumpdir entries.
+pub fn is_dir(entry_type: EntryType) -> bool {
+    entry_type.is_dir() || incremental::is_dumpdir(entry_type)
+}
+
+/// Drops `.` components so `./dir/file` and `dir/file` compare equal.
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// Unpacks the entries of the archive read from `reader` into `dst` for
+/// which `select` returns true; it gets each entry's index in the archive,
+/// its normalized path and whether it is a directory. Unsafe entries, and
+/// hard links to files that were not extracted, are reported and skipped,
+/// and the extraction fails at the end if there were any. Exceeding one of
+/// the [`Limits`] aborts the extraction. Directory owners, modes and times
+/// are applied last, deepest first, so a read-only directory cannot block
+/// writing its contents and writing the contents does not disturb the
+/// directory's restored mtime.
+pub fn extract<R: Read>(
+    reader: R,
+    dst: &Path,
+    options: &ExtractOptions,
+    mut select: impl FnMut(usize, &Path, bool) -> bool,
+) -> io::Result<()> {
+    let (tap, pax) = PaxTap::new(reader);
+    let mut archive = Archive::new(tap);
+    let mut extractor = Extractor {
+        dest: Destination::open(dst, options.unsafe_paths)?,
+        options,
+        owners: Owners::new(),
+        pax,
+        usage: Usage::new(options.limits),
+        directories: Vec::new(),
+        unlinked: 0,
+    };
+
+    let mut rejected = 0;
+    for (index, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        extractor.usage.count_entry()?;
+        let records = extractor.pax.take(entry.raw_header_position());
+        // A PAX sparse file is stored under a made-up name; its own is in
+        // the records.
+        let sparse = PaxSparse::from_records(&records)?;
+        let path = match &sparse {
+            Some(sparse) => normalize(&sparse.name),
+            None => normalize(&entry.path()?),
+        };
+        let is_dir = is_dir(entry.header().entry_type());
+        if !select(index, &path, is_dir) {
+            continue;
+        }
+        if let Err(e) = extractor.unpack(&mut entry, &path, &records, sparse) {
+            match dest::as_rejection(&e) {
+                Some(reason) => {
+                    eprintln!("❌ Rejected {}: {}", path.display(), reason);
+                    rejected += 1;
+                }
+                None => return Err(e),
+            }
+        }
+    }
+    extractor.finish_directories()?;
+
+    if rejected > 0 {
+        let message = match rejected {
+            1 => "1 unsafe entry was rejected".to_string(),
+            n => format!("{} unsafe entries were rejected", n),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    if extractor.unlinked > 0 {
+        let message = match extractor.unlinked {
+            1 => "1 hard link could not be made as its target was not extracted".to_string(),
+            n => format!("{} hard links could not be made as their targets were not extracted", n),
+        };
+        return Err(io::Error::new(io::ErrorKind::NotFound, message));
+    }
+    Ok(())
+}
+
+struct Extractor<'a> {
+    dest: Destination,
+    options: &'a ExtractOptions,
+    owners: Owners,
+    pax: PaxRecords,
+    usage: Usage,
+    directories: Vec<(PathBuf, Metadata)>,
+    // Hard links left out because the file they link to is not there, e.g.
+    // when it was not selected or was excluded.
+    unlinked: usize,
+}
+
+/// What is restored on an extracted file or directory once its contents
+/// are in place.
+struct Metadata {
+    owner: Option<(u32, u32)>,
+    mode: u32,
+    xattrs: Vec<Xattr>,
+    mtime: u64,
+}
+
+impl Metadata {
+    // Owner first, then mode, then times; see `dest::set_owner`. An access
+    // ACL refines the mode, so it goes after it. Like GNU tar, a failed chown
+    // (typically EPERM when not root) is reported but does not stop the
+    // extraction.
+    fn apply(&self, file: &File, path: &Path) -> io::Result<()> {
+        if let Some((uid, gid)) = self.owner {
+            if let Err(e) = dest::set_owner(file, uid, gid) {
+                warn_chown(path, e);
+            }
+        }
+        dest::set_mode(file, self.mode)?;
+        xattrs::apply(file, path, &self.xattrs);
+        dest::set_times(file, self.mtime)
+    }
+
+    // The same for an entry that is not opened; see `Destination::mknod`.
+    // Symlinks have no mode of their own and take no xattrs here.
+    fn apply_at(&self, dest: &Destination, rel: &Path, is_symlink: bool) -> io::Result<()> {
+        if let Some((uid, gid)) = self.owner {
+            if let Err(e) = dest.set_owner_at(rel, uid, gid) {
+                warn_chown(rel, e);
+            }
+        }
+        if !is_symlink {
+            dest.set_mode_at(rel, self.mode)?;
+        }
+        dest.set_times_at(rel, self.mtime)
+    }
+}
+
+fn warn_chown(path: &Path, e: io::Error) {
+    eprintln!("⚠️ Cannot change ownership of {}: {}", path.display(), e);
+}
+
+impl Extractor<'_> {
+    fn unpack<R: Read>(
+        &mut self,
+        entry: &mut Entry<R>,
+        path: &Path,
+        records: &[(String, Vec<u8>)],
+        sparse: Option<PaxSparse>,
+    ) -> io::Result<()> {
+        let options = self.options;
+        let Some(target) = options.destination(path)? else {
+            return Ok(());
+        };
+        self.usage.start_entry(path, sparse.as_ref().map_or(entry.size(), |sparse| sparse.real_size))?;
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let meta = Metadata {
+            owner: options.owner(header, &self.owners)?,
+            mode: options.mode(header)?,
+            xattrs: xattrs::from_pax(records, options.attrs, &self.owners, options.numeric_owner, path),
+            mtime: header.mtime()?,
+        };
+
+        if is_dir(entry_type) {
+            self.dest.create_dir(&target)?;
+            if options.incremental && incremental::is_dumpdir(entry_type) {
+                self.remove_unlisted(entry, path, &target)?;
+            }
+            self.directories.push((target, meta));
+        } else if entry_type.is_symlink() {
+            let link_name = entry.link_name()?.unwrap_or_default();
+            self.dest.symlink(&target, &link_name)?;
+            meta.apply_at(&self.dest, &target, true)?;
+        } else if entry_type.is_hard_link() {
+            let link_name = entry.link_name()?.unwrap_or_default();
+            let Some(source) = options.destination(&link_name).map_err(|e| match dest::as_rejection(&e) {
+                Some(reason) => dest::rejected(format!("hard link target: {}", reason)),
+                None => e,
+            })?
+            else {
+                eprintln!("⚠️ Skipping hard link {} to {}", path.display(), link_name.display());
+                return Ok(());
+            };
+            // Archives store a file once, under its first name, so the
+            // link can only be made if that entry was extracted.
+            match self.dest.hard_link(&target, &source) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    eprintln!(
+                        "❌ Cannot link {} to {}, which was not extracted",
+                        path.display(),
+                        link_name.display()
+                    );
+                    self.unlinked += 1;
+                }
+                result => result?,
+            }
+        } else if entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse() {
+            let mut file = self.dest.create_file(&target)?;
+            if let Some(sparse) = sparse {
+                let mut holes = HoleWriter::new(&file);
+                let mut data = PaxSparseReader::new(entry, sparse.real_size)?;
+                io::copy(&mut data, &mut CountingWriter { inner: &mut holes, usage: &mut self.usage, path })?;
+                holes.finish()?;
+            } else if entry_type.is_gnu_sparse() {
+                let mut holes = HoleWriter::new(&file);
+                io::copy(entry, &mut CountingWriter { inner: &mut holes, usage: &mut self.usage, path })?;
+                holes.finish()?;
+            } else {
+                io::copy(entry, &mut CountingWriter { inner: &mut file, usage: &mut self.usage, path })?;
+            }
+            meta.apply(&file, &target)?;
+        } else if entry_type.is_fifo() || entry_type.is_character_special() || entry_type.is_block_special() {
+            let file_type = match entry_type {
+                EntryType::Fifo => FileType::Fifo,
+                EntryType::Char => FileType::CharacterDevice,
+                _ => FileType::BlockDevice,
+            };
+            if file_type != FileType::Fifo && !dest::is_root() {
+                eprintln!("⚠️ Skipping device {}: creating device nodes requires root", path.display());
+                return Ok(());
+            }
+            let major = header.device_major()?.unwrap_or(0);
+            let minor = header.device_minor()?.unwrap_or(0);
+            self.dest.mknod(&target, file_type, rfs::makedev(major, minor))?;
+            meta.apply_at(&self.dest, &target, false)?;
+        } else if entry_type != EntryType::XGlobalHeader {
+            eprintln!("⚠️ Skipping {}: unsupported entry type {:?}", path.display(), entry_type);
+        }
+        Ok(())
+    }
+
+    // With --incremental, a dumpdir lists everything its directory held when
+    // the archive was made, so anything else in the extracted directory was
+    // deleted since and is removed. The listed names go through the same
+    // mapping as entries, so they match what was extracted for them.
+    fn remove_unlisted<R: Read>(&mut self, entry: &mut Entry<R>, path: &Path, target: &Path) -> io::Result<()> {
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let mut keep = HashSet::new();
+        for name in incremental::listed_names(&data) {
+            if let Ok(Some(child)) = self.options.destination(&path.join(&name)) {
+                if child.parent() == Some(target) {
+                    keep.extend(child.file_name().map(|n| n.to_os_string()));
+                }
+            }
+        }
+        self.dest.remove_unlisted(target, &keep)
+    }
+
+    // Deepest first, so a read-only directory is not locked before its
+    // subdirectories are done.
+    fn finish_directories(&mut self) -> io::Result<()> {
+        self.directories.sort_by(|a, b| b.0.cmp(&a.0));
+        for (path, meta) in &self.directories {
+            let file = self.dest.open_dir(path)?;
+            meta.apply(&file, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::fs;
+    use tar::Builder;
+
+    /// Extraction settings as for an ordinary user with no flags given.
+    pub fn options() -> ExtractOptions {
+        ExtractOptions {
+            codec: None,
+            filter: Filter::new(&[], &[]).unwrap(),
+            strip_components: 0,
+            transforms: Vec::new(),
+            unsafe_paths: false,
+            limits: Limits::default(),
+            preserve_permissions: false,
+            same_owner: false,
+            numeric_owner: false,
+            umask: 0o022,
+            attrs: Restore {
+                xattrs: false,
+                acls: false,
+                selinux: false,
+            },
+            incremental: false,
+            keys: Keys {
+                passphrase: None,
+                identities: Vec::new(),
+            },
+            trusted_key: None,
+        }
+    }
+
+    /// Appends an entry with its name and link target written straight
+    /// into the header, past the checks the tar crate makes on them, as a
+    /// hostile archive would have them.
+    pub fn append_raw(tar: &mut Builder<Vec<u8>>, entry_type: EntryType, name: &str, link: &str, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        let old = header.as_old_mut();
+        old.name[..name.len()].copy_from_slice(name.as_bytes());
+        old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.append(&header, data).unwrap();
+    }
+
+    pub fn extract_all(archive: &[u8], dst: &Path, options: &ExtractOptions) -> io::Result<()> {
+        extract(archive, dst, options, |_, _, _| true)
+    }
+
+    #[test]
+    fn hostile_entries_are_rejected_and_the_rest_extracted() {
+        let mut tar = Builder::new(Vec::new());
+        append_raw(&mut tar, EntryType::Regular, "good.txt", "", b"good");
+        append_raw(&mut tar, EntryType::Regular, "../escape.txt", "", b"escaped");
+        append_raw(&mut tar, EntryType::Regular, "/tmp/absolute.txt", "", b"absolute");
+        append_raw(&mut tar, EntryType::Symlink, "up", "../outside", b"");
+        append_raw(&mut tar, EntryType::Symlink, "root", "/", b"");
+        append_raw(&mut tar, EntryType::Directory, "sub", "", b"");
+        append_raw(&mut tar, EntryType::Symlink, "sub/back", "..", b"");
+        append_raw(&mut tar, EntryType::Regular, "sub/back/inside.txt", "", b"through an inner link");
+        append_raw(&mut tar, EntryType::Symlink, "ln", "sub/back/..", b"");
+        append_raw(&mut tar, EntryType::Link, "passwd", "../etc/passwd", b"");
+        append_raw(&mut tar, EntryType::Link, "shadow", "/etc/shadow", b"");
+        append_raw(&mut tar, EntryType::Regular, "last.txt", "", b"last");
+        let archive = tar.into_inner().unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dst = tmp.path().join("dest");
+        let err = extract_all(&archive, &dst, &options()).unwrap_err();
+        assert_eq!(err.to_string(), "8 unsafe entries were rejected");
+
+        assert_eq!(fs::read_to_string(dst.join("good.txt")).unwrap(), "good");
+        assert_eq!(fs::read_to_string(dst.join("last.txt")).unwrap(), "last");
+        // A link that stays inside is fine, but is never written through.
+        assert_eq!(fs::read_link(dst.join("sub/back")).unwrap(), Path::new(".."));
+        assert!(!dst.join("inside.txt").exists());
+        for rejected in ["up", "root", "ln", "passwd", "shadow"] {
+            assert!(fs::symlink_metadata(dst.join(rejected)).is_err(), "{} was created", rejected);
+        }
+        let mut outside: Vec<_> = fs::read_dir(tmp.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        outside.sort();
+        assert_eq!(outside, ["dest"]);
+    }
+
+    #[test]
+    fn a_hard_link_to_an_unselected_file_is_reported_and_skipped() {
+        let mut tar = Builder::new(Vec::new());
+        append_raw(&mut tar, EntryType::Regular, "src/a.txt", "", b"a");
+        append_raw(&mut tar, EntryType::Link, "src/hard", "src/a.txt", b"");
+        append_raw(&mut tar, EntryType::Regular, "src/sub/b.txt", "", b"b");
+        let archive = tar.into_inner().unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut selection = Selection::new(&["src/hard", "src/sub"]).unwrap();
+        let filter = Filter::new(&[], &[]).unwrap();
+        let err = extract(archive.as_slice(), tmp.path(), &options(), |_, path, is_dir| {
+            is_selected(path, is_dir, &mut selection, &filter)
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "1 hard link could not be made as its target was not extracted");
+        assert!(!tmp.path().join("src/hard").exists());
+        assert_eq!(fs::read_to_string(tmp.path().join("src/sub/b.txt")).unwrap(), "b");
+
+        let mut excluding = options();
+        excluding.filter = Filter::new(&[], &["a.txt"]).unwrap();
+        let dst = tmp.path().join("excluded");
+        let err = extract(archive.as_slice(), &dst, &excluding, |_, path, is_dir| {
+            is_selected(path, is_dir, &mut Selection::new(&[]).unwrap(), &excluding.filter)
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(dst.join("src/sub/b.txt").exists());
+
+        extract_all(&archive, &tmp.path().join("all"), &options()).unwrap();
+        assert_eq!(fs::read_to_string(tmp.path().join("all/src/hard")).unwrap(), "a");
+    }
+
+    #[test]
+    fn entries_are_not_written_through_an_archived_symlink() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("outside")).unwrap();
+        let mut tar = Builder::new(Vec::new());
+        append_raw(&mut tar, EntryType::Symlink, "link", "../outside", b"");
+        append_raw(&mut tar, EntryType::Regular, "link/x", "", b"x");
+        let archive = tar.into_inner().unwrap();
+
+        let mut options = options();
+        let dst = tmp.path().join("dest");
+        let err = extract_all(&archive, &dst, &options).unwrap_err();
+        assert_eq!(err.to_string(), "1 unsafe entry was rejected");
+        assert!(!tmp.path().join("outside/x").exists());
+
+        // A trusted archive may do both.
+        options.unsafe_paths = true;
+        extract_all(&archive, &tmp.path().join("trusted"), &options).unwrap();
+        assert!(tmp.path().join("outside/x").exists());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use tar::EntryType;
+
+use crate::walk;
+
+const SNAPSHOT_MAGIC: &[u8] = b"tart-snapshot-1\n";
+
+/// GNU tar's dumpdir entry type: a directory whose data lists the names it
+/// held when the archive was made, so extraction can delete the others.
+pub const DUMPDIR: u8 = b'D';
+
+// Dumpdir codes: `Y` for a file stored in this archive, `N` for one that is
+// unchanged and stored in an earlier one, `D` for a subdirectory.
+const IN_ARCHIVE: u8 = b'Y';
+const UNCHANGED: u8 = b'N';
+const DIRECTORY: u8 = b'D';
+
+pub fn is_dumpdir(entry_type: EntryType) -> bool {
+    entry_type == EntryType::new(DUMPDIR)
+}
+
+/// What --listed-incremental compares to decide whether a file changed
+/// since the previous run.
+#[derive(PartialEq, Eq)]
+struct FileState {
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+}
+
+impl FileState {
+    fn of(meta: &fs::Metadata) -> FileState {
+        FileState {
+            ino: meta.ino(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            size: meta.len(),
+        }
+    }
+}
+
+/// The snapshot file of --listed-incremental: the state of every file the
+/// previous run saw, by archive name, and the states seen by this run,
+/// which replace them once the archive is written.
+pub struct Snapshot {
+    path: PathBuf,
+    previous: HashMap<PathBuf, FileState>,
+    current: HashMap<PathBuf, FileState>,
+}
+
+impl Snapshot {
+    /// Reads the snapshot at `path`. A missing or empty file means a level-0
+    /// run, which archives everything.
+    pub fn load(path: &Path) -> io::Result<Snapshot> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut previous = HashMap::new();
+        if !data.is_empty() {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a tart snapshot file", path.display()),
+                )
+            };
+            let records = data.strip_prefix(SNAPSHOT_MAGIC).ok_or_else(invalid)?;
+            // One `ino mtime nsec size name` record per file, NUL-terminated
+            // since names may contain newlines.
+            for record in records.split(|&b| b == 0).filter(|r| !r.is_empty()) {
+                let mut fields = record.splitn(5, |&b| b == b' ');
+                let mut number = || -> io::Result<i64> {
+                    let field = fields.next().ok_or_else(invalid)?;
+                    std::str::from_utf8(field).ok().and_then(|f| f.parse().ok()).ok_or_else(invalid)
+                };
+                let state = FileState {
+                    ino: number()? as u64,
+                    mtime: number()?,
+                    mtime_nsec: number()?,
+                    size: number()? as u64,
+                };
+                let name = fields.next().ok_or_else(invalid)?;
+                previous.insert(PathBuf::from(OsString::from_vec(name.to_vec())), state);
+            }
+        }
+        Ok(Snapshot {
+            path: path.to_path_buf(),
+            previous,
+            current: HashMap::new(),
+        })
+    }
+
+    /// Records what this run sees at `name` and returns whether it changed
+    /// since the previous run. New files count as changed.
+    fn update(&mut self, name: &Path, meta: &fs::Metadata) -> bool {
+        let state = FileState::of(meta);
+        let changed = self.previous.get(name) != Some(&state);
+        self.current.insert(name.to_path_buf(), state);
+        changed
+    }
+
+    /// Writes this run's states back, replacing the snapshot file only once
+    /// the new one is complete.
+    pub fn save(&self) -> io::Result<()> {
+        let mut tmp_name = self.path.clone().into_os_string();
+        tmp_name.push(".tart-tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let result = self.write_to(&tmp_path);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        let mut names: Vec<&PathBuf> = self.current.keys().collect();
+        names.sort();
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(SNAPSHOT_MAGIC)?;
+        for name in names {
+            let state = &self.current[name];
+            write!(out, "{} {} {} {} ", state.ino, state.mtime, state.mtime_nsec, state.size)?;
+            out.write_all(name.as_os_str().as_bytes())?;
+            out.write_all(&[0])?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+/// An entry chosen by [`plan`], with the dumpdir data of a directory.
+pub struct Planned {
+    pub name: PathBuf,
+    pub src: PathBuf,
+    pub dumpdir: Option<Vec<u8>>,
+}
+
+/// Decides what an incremental run archives out of `entries`, given as
+/// (archive name, source path) pairs: every directory, as a dumpdir that
+/// lists its children, plus the files that changed since the snapshot.
+/// Unchanged files only appear in their directory's listing.
+pub fn plan(entries: Vec<(PathBuf, PathBuf)>, snapshot: &mut Snapshot, dereference: bool) -> io::Result<Vec<Planned>> {
+    let mut codes = Vec::with_capacity(entries.len());
+    let mut children: HashMap<PathBuf, Vec<(OsString, u8)>> = HashMap::new();
+    for (name, src) in &entries {
+        let meta = walk::metadata(src, dereference)?;
+        let code = if meta.is_dir() {
+            DIRECTORY
+        } else if snapshot.update(name, &meta) {
+            IN_ARCHIVE
+        } else {
+            UNCHANGED
+        };
+        if let (Some(parent), Some(file_name)) = (name.parent(), name.file_name()) {
+            children.entry(parent.to_path_buf()).or_default().push((file_name.to_os_string(), code));
+        }
+        codes.push(code);
+    }
+
+    let mut planned = Vec::new();
+    for ((name, src), code) in entries.into_iter().zip(codes) {
+        let dumpdir = match code {
+            DIRECTORY => Some(dumpdir(children.remove(&name).unwrap_or_default())),
+            IN_ARCHIVE => None,
+            _ => continue,
+        };
+        planned.push(Planned { name, src, dumpdir });
+    }
+    Ok(planned)
+}
+
+// Dumpdir data is one `<code><name>\0` record per child in name order,
+// ending with an empty record.
+fn dumpdir(mut children: Vec<(OsString, u8)>) -> Vec<u8> {
+    children.sort();
+    let mut data = Vec::new();
+    for (name, code) in children {
+        data.push(code);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+    }
+    data.push(0);
+    data
+}
+
+/// The names a dumpdir says its directory holds. GNU tar's rename records
+/// and anything else unknown are ignored.
+pub fn listed_names(data: &[u8]) -> Vec<OsString> {
+    data.split(|&b| b == 0)
+        .take_while(|record| !record.is_empty())
+        .filter_map(|record| match record.split_first() {
+            Some((&(IN_ARCHIVE | UNCHANGED | DIRECTORY), name)) if !name.is_empty() => {
+                Some(OsString::from_vec(name.to_vec()))
+            }
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,290 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// The ratio is only checked once this much has been decompressed: tar
+// padding alone makes tiny archives compress far better than real data.
+const RATIO_GRACE_BYTES: u64 = 1 << 20;
+
+/// Caps on what an extraction may produce. `None` means unlimited.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub max_total_size: Option<u64>,
+    pub max_entries: Option<u64>,
+    pub max_file_size: Option<u64>,
+    pub max_depth: Option<usize>,
+    pub max_ratio: Option<f64>,
+}
+
+/// The error carried inside `io::Error` when a limit is hit.
+#[derive(Debug)]
+pub struct LimitExceeded(String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "extraction limit exceeded: {}", self.0)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+fn exceeded(reason: String) -> io::Error {
+    io::Error::other(LimitExceeded(reason))
+}
+
+/// Running totals for one extraction, checked against its [`Limits`].
+pub struct Usage {
+    limits: Limits,
+    entries: u64,
+    total: u64,
+    file: u64,
+}
+
+impl Usage {
+    pub fn new(limits: Limits) -> Usage {
+        Usage {
+            limits,
+            entries: 0,
+            total: 0,
+            file: 0,
+        }
+    }
+
+    /// Counts an entry read from the archive, whether or not it is extracted.
+    pub fn count_entry(&mut self) -> io::Result<()> {
+        self.entries += 1;
+        match self.limits.max_entries {
+            Some(max) if self.entries > max => {
+                Err(exceeded(format!("more than {} entries (--max-entries)", max)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks an entry about to be extracted against the depth limit and,
+    /// using the size its header declares, the size limits. Catching an
+    /// oversized entry here avoids writing any of it. A hostile header can
+    /// declare any size, so the sum saturates rather than wrapping around
+    /// to a small total.
+    pub fn start_entry(&mut self, path: &Path, declared_size: u64) -> io::Result<()> {
+        self.file = 0;
+        let depth = path.components().count();
+        if let Some(max) = self.limits.max_depth {
+            if depth > max {
+                return Err(exceeded(format!(
+                    "{} is {} levels deep, more than {} (--max-depth)",
+                    path.display(),
+                    depth,
+                    max
+                )));
+            }
+        }
+        self.check(path, declared_size, self.total.saturating_add(declared_size))
+    }
+
+    /// Counts bytes actually written for the current entry. Sparse entries
+    /// can expand beyond their declared size, so the limits are enforced on
+    /// this too.
+    pub fn add_bytes(&mut self, path: &Path, n: u64) -> io::Result<()> {
+        self.file = self.file.saturating_add(n);
+        self.total = self.total.saturating_add(n);
+        self.check(path, self.file, self.total)
+    }
+
+    fn check(&self, path: &Path, file: u64, total: u64) -> io::Result<()> {
+        if let Some(max) = self.limits.max_file_size {
+            if file > max {
+                return Err(exceeded(format!(
+                    "{} is larger than {} bytes (--max-file-size)",
+                    path.display(),
+                    max
+                )));
+            }
+        }
+        if let Some(max) = self.limits.max_total_size {
+            if total > max {
+                return Err(exceeded(format!(
+                    "more than {} bytes in total (--max-total-size)",
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A writer that reports every write to a [`Usage`].
+pub struct CountingWriter<'a, W> {
+    pub inner: W,
+    pub usage: &'a mut Usage,
+    pub path: &'a Path,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.usage.add_bytes(self.path, buf.len() as u64)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counts the bytes read through it into a shared cell, so the compressed
+/// side of a decoder can be measured from the decompressed side.
+pub struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R, count: Rc<Cell<u64>>) -> Self {
+        CountingReader { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Wraps a decoder and fails once its output outgrows the compressed input
+/// read so far by more than `max_ratio`.
+pub struct RatioGuard<R> {
+    inner: R,
+    compressed: Rc<Cell<u64>>,
+    decompressed: u64,
+    max_ratio: Option<f64>,
+}
+
+impl<R> RatioGuard<R> {
+    pub fn new(inner: R, compressed: Rc<Cell<u64>>, max_ratio: Option<f64>) -> Self {
+        RatioGuard {
+            inner,
+            compressed,
+            decompressed: 0,
+            max_ratio,
+        }
+    }
+}
+
+impl<R: Read> Read for RatioGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.decompressed += n as u64;
+        if let Some(max) = self.max_ratio {
+            let compressed = self.compressed.get().max(1);
+            if self.decompressed > RATIO_GRACE_BYTES && self.decompressed as f64 > compressed as f64 * max {
+                return Err(exceeded(format!(
+                    "{} bytes decompressed from {}, a ratio above {} (--max-ratio)",
+                    self.decompressed, compressed, max
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::tests::{append_raw, extract_all, options};
+    use tar::{Builder, EntryType};
+
+    fn is_exceeded(result: io::Result<impl fmt::Debug>) -> bool {
+        result.as_ref().err().and_then(|e| e.get_ref()).is_some_and(|e| e.is::<LimitExceeded>())
+    }
+
+    #[test]
+    fn entries_and_depth_are_capped() {
+        let mut usage = Usage::new(Limits {
+            max_entries: Some(2),
+            max_depth: Some(2),
+            ..Limits::default()
+        });
+        usage.count_entry().unwrap();
+        usage.count_entry().unwrap();
+        assert!(is_exceeded(usage.count_entry()));
+        usage.start_entry(Path::new("a/b"), 0).unwrap();
+        assert!(is_exceeded(usage.start_entry(Path::new("a/b/c"), 0)));
+    }
+
+    #[test]
+    fn sizes_are_checked_before_and_while_writing() {
+        let mut usage = Usage::new(Limits {
+            max_file_size: Some(100),
+            max_total_size: Some(150),
+            ..Limits::default()
+        });
+        // Declared sizes are refused before anything is written.
+        assert!(is_exceeded(usage.start_entry(Path::new("big"), 101)));
+        usage.start_entry(Path::new("a"), 0).unwrap();
+        usage.add_bytes(Path::new("a"), 100).unwrap();
+        // A sparse entry can write more than it declared.
+        assert!(is_exceeded(usage.add_bytes(Path::new("a"), 1)));
+        usage.start_entry(Path::new("b"), 49).unwrap();
+        assert!(is_exceeded(usage.start_entry(Path::new("c"), 51)));
+    }
+
+    #[test]
+    fn huge_declared_sizes_count_as_over_the_limit() {
+        let mut usage = Usage::new(Limits {
+            max_total_size: Some(150),
+            ..Limits::default()
+        });
+        usage.start_entry(Path::new("a"), 0).unwrap();
+        usage.add_bytes(Path::new("a"), 100).unwrap();
+        assert!(is_exceeded(usage.start_entry(Path::new("bomb"), u64::MAX)));
+        assert!(is_exceeded(usage.start_entry(Path::new("bomb"), u64::MAX - 50)));
+
+        let mut unlimited = Usage::new(Limits::default());
+        unlimited.start_entry(Path::new("a"), 0).unwrap();
+        unlimited.add_bytes(Path::new("a"), 100).unwrap();
+        unlimited.start_entry(Path::new("bomb"), u64::MAX).unwrap();
+    }
+
+    #[test]
+    fn the_ratio_is_checked_past_the_grace_bytes() {
+        let zeros = io::repeat(0).take(4 * RATIO_GRACE_BYTES);
+        let compressed = Rc::new(Cell::new(1000));
+        let mut guard = RatioGuard::new(zeros, Rc::clone(&compressed), Some(100.0));
+        let mut buf = vec![0; RATIO_GRACE_BYTES as usize];
+        guard.read_exact(&mut buf).unwrap();
+        assert!(is_exceeded(guard.read_exact(&mut buf)));
+
+        let zeros = io::repeat(0).take(4 * RATIO_GRACE_BYTES);
+        let mut unlimited = RatioGuard::new(zeros, compressed, None);
+        assert_eq!(io::copy(&mut unlimited, &mut io::sink()).unwrap(), 4 * RATIO_GRACE_BYTES);
+    }
+
+    #[test]
+    fn extraction_stops_at_a_limit() {
+        let mut tar = Builder::new(Vec::new());
+        append_raw(&mut tar, EntryType::Regular, "small", "", &[1; 10]);
+        append_raw(&mut tar, EntryType::Regular, "large", "", &[2; 1000]);
+        append_raw(&mut tar, EntryType::Regular, "after", "", &[3; 10]);
+        let archive = tar.into_inner().unwrap();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut options = options();
+        options.limits.max_file_size = Some(100);
+        let result = extract_all(&archive, tmp.path(), &options);
+        assert!(is_exceeded(result));
+        assert!(tmp.path().join("small").exists());
+        assert!(!tmp.path().join("large").exists() && !tmp.path().join("after").exists());
+
+        options.limits = Limits {
+            max_entries: Some(2),
+            ..Limits::default()
+        };
+        assert!(is_exceeded(extract_all(&archive, &tmp.path().join("entries"), &options)));
+        assert!(!tmp.path().join("entries/after").exists());
+    }
+}
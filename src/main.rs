@@ -1,73 +1,488 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter};
-use std::path::Path;
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
 use clap::{Arg, Command};
-use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use tar::{Archive, Builder, Header};
-
-fn compress_files(input_files: &[&str], output: &str) -> io::Result<()> {
-    let tar_gz = File::create(output)?;
-    let encoder = GzEncoder::new(BufWriter::new(tar_gz), Compression::default());
+use tar::{Archive, Builder, EntryType};
+
+mod codec;
+mod create;
+mod crypt;
+mod dest;
+mod diff;
+mod extract;
+mod incremental;
+mod limits;
+mod owner;
+mod parallel_gzip;
+mod pax;
+mod restore;
+mod sign;
+mod sparse;
+mod transform;
+mod verify;
+mod volume;
+mod walk;
+mod xattrs;
+
+use codec::{open_decoder, Codec, Level, CODEC_NAMES};
+use create::{append_inputs, CreateOptions};
+use crypt::{EncryptWriter, Encryption, Keys};
+use extract::{ExtractOptions, Selection};
+use incremental::Snapshot;
+use limits::{CountingReader, Limits, RatioGuard};
+use sign::SignWriter;
+use transform::Transform;
+use volume::{VolumeReader, VolumeWriter};
+use walk::Filter;
+
+fn compress_files(input_files: &[&str], output: &str, options: &CreateOptions) -> io::Result<()> {
+    if output == volume::STDIO && options.sign_key.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--sign needs an output file to put the .sig next to",
+        ));
+    }
+    let codec = options.codec.unwrap_or(Codec::Gzip);
+    codec.check_level(options.level)?;
+    let mut snapshot = options.listed_incremental.as_deref().map(Snapshot::load).transpose()?;
+    let tar_gz = VolumeWriter::create(Path::new(output), options.split_size)?;
+    let out = SignWriter::new(BufWriter::new(tar_gz), options.sign_key.as_ref());
+    let out = EncryptWriter::new(out, &options.encryption)?;
+    let encoder = codec.encoder(out, options.level, options.threads)?;
     let mut tar = Builder::new(encoder);
 
-    for file in input_files {
-        let file_path = Path::new(file);
-        if file_path.exists() {
-            tar.append_path(file_path)?;
-        } else {
-            eprintln!("⚠️ Skipping missing file: {}", file);
+    let unreadable = append_inputs(&mut tar, input_files, options, snapshot.as_mut())?;
+
+    let (out, signed) = tar.into_inner()?.finish()?.finish()?.finish();
+    let volumes = out.into_inner().map_err(|e| e.into_error())?.finish()?;
+    if let Some((key, digest)) = signed {
+        sign::write_signature(&sign::signature_path(Path::new(output)), key, &digest)?;
+    }
+    if let Some(snapshot) = snapshot {
+        snapshot.save()?;
+    }
+    walk::check_unreadable(unreadable)?;
+    if options.split_size.is_some() {
+        eprintln!(
+            "✅ Compressed {} files into {} volumes {} to {}",
+            input_files.len(),
+            volumes,
+            volume::part_path(Path::new(output), 0).display(),
+            volume::part_path(Path::new(output), volumes - 1).display()
+        );
+    } else {
+        eprintln!("✅ Compressed {} files into {}", input_files.len(), output);
+    }
+    Ok(())
+}
+
+fn decompress_files(
+    input: &str,
+    members: &[&str],
+    output_dir: &str,
+    options: &ExtractOptions,
+) -> io::Result<()> {
+    let decoder = open_for_extraction(input, options)?;
+    let mut selection = Selection::new(members)?;
+
+    extract::extract(decoder, Path::new(output_dir), options, |_, path, is_dir| {
+        extract::is_selected(path, is_dir, &mut selection, &options.filter)
+    })?;
+    selection.check_all_found()?;
+    eprintln!("✅ Extracted contents of {} to {}", input, output_dir);
+    Ok(())
+}
+
+// Opens an archive to extract with --max-ratio watching the decompression.
+fn open_for_extraction<'a>(input: &str, options: &ExtractOptions) -> io::Result<RatioGuard<Box<dyn io::Read + 'a>>> {
+    let tar_gz = open_archive(input, options)?;
+    let end_check = tar_gz.end_check();
+    let compressed = Rc::new(Cell::new(0));
+    let reader = CountingReader::new(BufReader::new(tar_gz), Rc::clone(&compressed));
+    Ok(RatioGuard::new(end_check.wrap(open_tar(reader, options)?), compressed, options.limits.max_ratio))
+}
+
+// Opens an archive to read through to its tar stream.
+fn open_decoded<'a>(input: &str, options: &ExtractOptions) -> io::Result<Box<dyn io::Read + 'a>> {
+    let tar_gz = open_archive(input, options)?;
+    let end_check = tar_gz.end_check();
+    Ok(end_check.wrap(open_tar(BufReader::new(tar_gz), options)?))
+}
+
+// Opens an archive file or the parts of a split one, first checking its
+// detached signature when --verify-signature is given. Nothing is read
+// from it unless that passes.
+fn open_archive(input: &str, options: &ExtractOptions) -> io::Result<VolumeReader> {
+    if input == volume::STDIO && options.trusted_key.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--verify-signature reads the archive twice, so it needs a file rather than standard input",
+        ));
+    }
+    let mut tar_gz = VolumeReader::open(Path::new(input))?;
+    if let Some(key) = &options.trusted_key {
+        let sig_path = sign::signature_path(tar_gz.base());
+        sign::check(&mut tar_gz, &sig_path, key)?;
+        tar_gz.rewind()?;
+        eprintln!("✅ Good signature on {} from {}", input, sign::key_id(key));
+    }
+    Ok(tar_gz)
+}
+
+// Gets at the tar stream in `reader`: decrypted if it is an age file, then
+// decompressed.
+fn open_tar<'a>(reader: impl io::Read + 'a, options: &ExtractOptions) -> io::Result<Box<dyn io::Read + 'a>> {
+    let (_, decrypted) = crypt::decrypt(reader, &options.keys)?;
+    let (_, decoder) = open_decoder(decrypted, options.codec)?;
+    Ok(decoder)
+}
+
+// Restores the tree as of `at` from archives given oldest first: a first
+// pass picks the entry to use for every path, a second extracts the picks
+// archive by archive, with dumpdirs removing what was deleted.
+fn restore_archives(inputs: &[&str], at: u64, output_dir: &str, options: &ExtractOptions) -> io::Result<()> {
+    if inputs.contains(&volume::STDIO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--restore-at reads each archive twice, so it needs files rather than standard input",
+        ));
+    }
+    let mut plan = restore::Plan::new(at);
+    for (archive, input) in inputs.iter().enumerate() {
+        plan.scan(archive, open_decoded(input, options)?)?;
+    }
+    let picks = plan.finish(inputs.len());
+    if picks.iter().all(|picked| picked.is_empty()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("nothing in the archives is as old as {}", format_mtime(at)),
+        ));
+    }
+
+    let mut all = Selection::new(&[])?;
+    for (input, picked) in inputs.iter().zip(&picks) {
+        if picked.is_empty() {
+            continue;
         }
+        let decoder = open_for_extraction(input, options)?;
+        extract::extract(decoder, Path::new(output_dir), options, |index, path, is_dir| {
+            picked.contains(&index) && extract::is_selected(path, is_dir, &mut all, &options.filter)
+        })?;
+    }
+    eprintln!("✅ Restored {} as of {} UTC to {}", inputs.join(", "), format_mtime(at), output_dir);
+    Ok(())
+}
+
+fn diff_archive(input: &str, members: &[&str], base: &str, options: &ExtractOptions) -> io::Result<()> {
+    let decoder = open_decoded(input, options)?;
+    let mut selection = Selection::new(members)?;
+
+    let drifted = diff::diff(decoder, Path::new(base), &mut selection, options)?;
+    selection.check_all_found()?;
+    if drifted > 0 {
+        return Err(io::Error::other(format!("{} entries differ from {}", drifted, base)));
     }
+    eprintln!("✅ {} matches {}", base, input);
+    Ok(())
+}
 
-    tar.finish()?;
-    println!("✅ Compressed {} files into {}", input_files.len(), output);
+fn verify_archive(input: &str, options: &ExtractOptions) -> io::Result<()> {
+    let decoder = open_decoded(input, options)?;
+    let report = verify::verify(decoder)?;
+    if report.failed > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} of {} entries failed", report.failed, report.passed + report.failed + report.unchecked),
+        ));
+    }
+    eprintln!(
+        "✅ Verified {}: {} entries passed, {} without a digest",
+        input, report.passed, report.unchecked
+    );
     Ok(())
 }
 
-fn decompress_files(input: &str, output_dir: &str) -> io::Result<()> {
-    let tar_gz = File::open(input)?;
-    let decoder = GzDecoder::new(BufReader::new(tar_gz));
+fn list_archive(
+    input: &str,
+    members: &[&str],
+    verbose: bool,
+    options: &ExtractOptions,
+) -> io::Result<()> {
+    let decoder = open_decoded(input, options)?;
     let mut archive = Archive::new(decoder);
+    let mut selection = Selection::new(members)?;
+    let mut out = io::stdout().lock();
 
-    archive.unpack(output_dir)?; // Extract all files into the output directory
-    println!("✅ Extracted contents of {} to {}", input, output_dir);
-    Ok(())
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let is_dir = extract::is_dir(entry.header().entry_type());
+        if !extract::is_selected(&extract::normalize(&entry.path()?), is_dir, &mut selection, &options.filter) {
+            continue;
+        }
+        let path = entry.path()?.display().to_string();
+        if !verbose {
+            writeln!(out, "{}", path)?;
+            continue;
+        }
+
+        let header = entry.header();
+        let owner = match header.username() {
+            Ok(Some(name)) if !name.is_empty() && !options.numeric_owner => name.to_string(),
+            _ => header.uid()?.to_string(),
+        };
+        let group = match header.groupname() {
+            Ok(Some(name)) if !name.is_empty() && !options.numeric_owner => name.to_string(),
+            _ => header.gid()?.to_string(),
+        };
+        let entry_type = header.entry_type();
+        let size = if entry_type.is_character_special() || entry_type.is_block_special() {
+            format!(
+                "{},{}",
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0)
+            )
+        } else {
+            entry.size().to_string()
+        };
+        let mut line = format!(
+            "{}{} {}/{} {:>8} {} {}",
+            entry_type_char(entry_type),
+            mode_string(header.mode()?),
+            owner,
+            group,
+            size,
+            format_mtime(header.mtime()?),
+            path
+        );
+        if let Some(target) = entry.link_name()? {
+            if entry_type.is_hard_link() {
+                line.push_str(&format!(" link to {}", target.display()));
+            } else {
+                line.push_str(&format!(" -> {}", target.display()));
+            }
+        }
+        writeln!(out, "{}", line)?;
+    }
+    selection.check_all_found()
+}
+
+fn entry_type_char(entry_type: EntryType) -> char {
+    match entry_type {
+        EntryType::Directory => 'd',
+        _ if incremental::is_dumpdir(entry_type) => 'd',
+        EntryType::Symlink => 'l',
+        EntryType::Link => 'h',
+        EntryType::Char => 'c',
+        EntryType::Block => 'b',
+        EntryType::Fifo => 'p',
+        _ => '-',
+    }
+}
+
+fn mode_string(mode: u32) -> String {
+    let mut s = String::with_capacity(9);
+    for (shift, special, set_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => set_char,
+            (false, true) => set_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
 }
-fn add_file_to_tar_gz(tar_gz_path: &str, file_path: &str) -> io::Result<()> {
-    let tar_gz_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(tar_gz_path)?;
 
-    let gz_encoder = GzEncoder::new(BufWriter::new(tar_gz_file), Compression::default());
-    let mut tar_builder = Builder::new(gz_encoder);
+// Formats a Unix timestamp as `YYYY-MM-DD HH:MM` in UTC.
+fn format_mtime(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60)
+}
 
-    let file = File::open(file_path)?;
-    let mut header = Header::new_gnu();
-    header.set_path(Path::new(file_path))?;
-    header.set_size(file.metadata()?.len());
-    header.set_mode(0o755);
-    header.set_mtime(0);
+// Parses a point in time in UTC: `@SECONDS`, `YYYY-MM-DD` (midnight),
+// `YYYY-MM-DD HH:MM` or `YYYY-MM-DD HH:MM:SS`, with a space or `T` between
+// date and time.
+fn parse_time(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid time '{}'", s);
+    if let Some(secs) = s.strip_prefix('@') {
+        return secs.parse().map_err(|_| invalid());
+    }
+    let (date, time) = s.split_once([' ', 'T']).unwrap_or((s, "00:00"));
+    let number = |field: Option<&str>| field.and_then(|f| f.parse::<i64>().ok()).ok_or_else(invalid);
+    let mut date_fields = date.splitn(3, '-');
+    let (year, month, day) = (number(date_fields.next())?, number(date_fields.next())?, number(date_fields.next())?);
+    let mut time_fields = time.splitn(3, ':');
+    let (hour, minute) = (number(time_fields.next())?, number(time_fields.next())?);
+    let second = time_fields.next().map_or(Ok(0), |f| number(Some(f)))?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+    // Days-from-civil, the inverse of the conversion in `format_mtime`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    u64::try_from(days * 86_400 + hour * 3600 + minute * 60 + second).map_err(|_| invalid())
+}
 
-    tar_builder.append(&header, file)?;
+// The archive is rewritten through a temporary file next to it: every existing
+// header and data block is copied verbatim (GNU long-name, PAX and sparse
+// extension blocks included), the new inputs go after them, and the result
+// replaces the original only once it has been fully written.
+fn add_files_to_archive(
+    tar_gz_path: &str,
+    input_files: &[&str],
+    options: &CreateOptions,
+    keys: &Keys,
+) -> io::Result<()> {
+    let path = Path::new(tar_gz_path);
+    if tar_gz_path == volume::STDIO {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--add rewrites the archive in place, so it needs a file rather than standard output",
+        ));
+    }
+    if options.split_size.is_some() || tar_gz_path.ends_with(".000") || (!path.exists() && volume::part_path(path, 0).exists()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "split archives cannot be appended to; create a new one instead",
+        ));
+    }
+    let tmp_path = format!("{}.tart-tmp", tar_gz_path);
+    let result = rewrite_with_appended(tar_gz_path, &tmp_path, input_files, options, keys);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    let (digest, unreadable) = result?;
+    fs::rename(&tmp_path, tar_gz_path)?;
+    let sig_path = sign::signature_path(Path::new(tar_gz_path));
+    match (&options.sign_key, digest) {
+        (Some(key), Some(digest)) => sign::write_signature(&sig_path, key, &digest)?,
+        _ if sig_path.exists() => {
+            eprintln!("⚠️ {} no longer matches the archive; sign it again with --sign", sig_path.display())
+        }
+        _ => {}
+    }
+    walk::check_unreadable(unreadable)?;
 
-    println!("✅ Added {} to {}", file_path, tar_gz_path);
+    eprintln!("✅ Added {} files to {}", input_files.len(), tar_gz_path);
     Ok(())
 }
+
+fn rewrite_with_appended(
+    tar_gz_path: &str,
+    tmp_path: &str,
+    input_files: &[&str],
+    options: &CreateOptions,
+    keys: &Keys,
+) -> io::Result<(Option<[u8; 32]>, usize)> {
+    let existing = File::open(tar_gz_path)?;
+    let permissions = existing.metadata()?.permissions();
+    let (encrypted, decrypted) = crypt::decrypt(BufReader::new(existing), keys)?;
+    if encrypted && matches!(options.encryption, Encryption::None) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the archive is encrypted; give --passphrase-file or --recipient to encrypt it again",
+        ));
+    }
+    let (detected, decoder) = open_decoder(decrypted, None)?;
+    let codec = options.codec.unwrap_or(detected);
+
+    let tmp = File::create(tmp_path)?;
+    tmp.set_permissions(permissions)?;
+    let out = SignWriter::new(BufWriter::new(tmp), options.sign_key.as_ref());
+    let out = EncryptWriter::new(out, &options.encryption)?;
+    let mut encoder = codec.encoder(out, options.level, options.threads)?;
+    pax::copy_entries(decoder, &mut encoder)?;
+    let mut tar = Builder::new(encoder);
+
+    let mut snapshot = options.listed_incremental.as_deref().map(Snapshot::load).transpose()?;
+    let unreadable = append_inputs(&mut tar, input_files, options, snapshot.as_mut())?;
+
+    let (out, signed) = tar.into_inner()?.finish()?.finish()?.finish();
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    if let Some(snapshot) = snapshot {
+        snapshot.save()?;
+    }
+    Ok((signed.map(|(_, digest)| digest), unreadable))
+}
+
+// Parses a byte count with an optional binary suffix: 512, 64K, 10M, 2G, 1T.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let (digits, shift) = match trimmed.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 10),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 20),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 30),
+        Some('T') => (&trimmed[..trimmed.len() - 1], 40),
+        _ => (trimmed, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+// Reads SOURCE_DATE_EPOCH, the reproducible-builds convention for a fixed
+// build time in seconds since the epoch. Unset or empty means none.
+fn source_date_epoch() -> io::Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.is_empty() => value.trim().parse().map(Some).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a number of seconds", value))
+        }),
+        _ => Ok(None),
+    }
+}
+
+// For commands whose output is a report on standard output: the reader of a
+// pipe going away early, as `head` does, ends the run without an error.
+fn ignore_closed_stdout(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+fn or_exit<T>(result: io::Result<T>, what: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("❌ {}: {}", what, e);
+        process::exit(1);
+    })
+}
+
 fn display_help() {
     println!(
         r#"
 NAME
-    tart - Compress and decompress files using Gzip and Tar
+    tart - Compress and decompress files using Tar and gzip, bzip2, xz, zstd or lz4
 
 SYNOPSIS
     tart [OPTIONS] -i <INPUT> -o <OUTPUT>
+    tart -t [-v] -i <ARCHIVE>
 
 DESCRIPTION
     Tart is a command-line utility to compress multiple files into a 
-    single .tar.gz archive, extract .tar.gz archives, or append a file to an 
+    single .tar.gz archive, extract .tar.gz archives, or append files to an 
     existing .tar.gz archive.
 
 OPTIONS
@@ -77,15 +492,261 @@ OPTIONS
     -d, --decompress
         Extract files from a .tar.gz archive.
     
+    -t, --list
+        List the contents of a .tar.gz archive without extracting it.
+
+    -v, --verbose
+        With --list, print type, permissions, owner/group, size, mtime (UTC)
+        and link target for each entry, like `tar -tvf`.
+
+    --verify
+        Read the whole archive and check it: the codec's checksums (gzip and
+        bzip2 CRCs, the xz check), every tar header checksum, and for each
+        file the SHA-256 digest tart stores in a TART.sha256 PAX record when
+        creating with --digest (GNU tar notes the record as an unknown
+        keyword and skips it). One tab-separated line per entry reports
+        PASS, FAIL or NODIGEST (a file archived without a digest, e.g.
+        without --digest or by another tar). Exits with status 1 on any
+        failure.
+
+    --digest
+        When creating or adding, store the SHA-256 of every regular file's
+        contents for --verify. The record has to precede the data, so each
+        file is read twice: once for the digest and once to archive it.
+        Files stored as sparse entries with -S get no digest, as the first
+        read would go through all of their holes.
+
+    --diff
+        Compare the archive with the file system: every entry (or the
+        selected members) is mapped to a path below -o (default: the current
+        directory) the way extraction would write it, and missing files,
+        type, content (by SHA-256), size, mode, owner, mtime, symlink target
+        and hard link differences are reported. Exits with status 1 when
+        anything differs.
+
     -a, --add
-        Add a file to an existing .tar.gz archive.
+        Append files or directories to an existing .tar.gz archive. Existing
+        entries are kept; the archive is rewritten through a temporary file.
     
+    --codec <CODEC>
+        Compression codec wrapped around the tar stream: gzip (default),
+        bzip2, xz, zstd, lz4, or none for a plain uncompressed tar.
+        When extracting, listing or adding, the codec is detected from the
+        archive's magic bytes and this option is only needed to override it.
+
+    --level <LEVEL>
+        Compression level for the chosen codec: 0-9 for gzip and xz, 1-9 for
+        bzip2, 1-22 for zstd. Append `e` for an xz extreme preset (e.g. 9e).
+        lz4 has no levels.
+
+    --fast, --best
+        Shorthand for the codec's fastest or highest-ratio level.
+
+    --threads <N>
+        Compress gzip archives on N threads, pigz-style: the tar stream is cut
+        into 1 MiB blocks that are compressed in parallel and written as
+        consecutive gzip members. 0 uses every available core. Default: 1.
+
+    --exclude <PATTERN>
+        Leave out files and directories matching PATTERN (repeatable), both
+        when creating and when extracting or listing.
+        Patterns are globs matched against archive-relative paths like GNU
+        tar: `*.o` or `node_modules` match at any depth, `target/` only
+        matches directories, and a leading `/` anchors to the archive root.
+        Excluded directories are not descended into.
+
+    --include <PATTERN>
+        Only archive files matching PATTERN, or anything below a matching
+        directory (repeatable). Parent directories are kept as needed.
+
+    --respect-ignore
+        Skip files ignored by .gitignore, .ignore and .tartignore files at
+        every level, with git's precedence rules (deeper files override
+        shallower ones, `!` re-includes). Ignore files above the input up to
+        the repository root and .git/info/exclude apply as well, and .git
+        itself is never archived.
+
+    --strip-components <N>
+        When extracting, drop the first N leading components from each member
+        name. Members with N or fewer components are skipped.
+
+    --transform <EXPRESSION>
+        Rewrite member names with a sed-style substitution such as
+        's,^old,new,' (repeatable, applied in order). Used when creating,
+        adding and extracting; on extraction it runs before
+        --strip-components and also rewrites hard link targets. The
+        replacement understands & and
+        \1 to \9. As in GNU tar the expression is a POSIX basic regex, so
+        groups are written \(...\). Flags: g (every match), i (ignore case),
+        x (extended regex syntax). Member selection and --exclude match the
+        names stored in the archive.
+
+    --unsafe-paths
+        Turn off the extraction safety checks for trusted archives. By
+        default every entry is created through an open handle on the output
+        directory without following symlinks, and entries are rejected (and
+        reported with the reason) when they have an absolute path or a ..
+        component, would be written through a symlink, or are symlinks or
+        hard links pointing outside the output directory. Rejected entries
+        make the extraction fail after the remaining entries are written.
+
+    --max-total-size <SIZE>, --max-file-size <SIZE>
+        Abort extraction once more than SIZE bytes would be written in total,
+        or to a single file. SIZE takes K, M, G or T suffixes (powers of 1024).
+
+    --max-entries <N>
+        Abort extraction after reading more than N archive entries.
+
+    --max-depth <N>
+        Abort extraction on an entry with more than N path components.
+
+    --max-ratio <RATIO>
+        Abort extraction once the decompressed data is more than RATIO times
+        the compressed input read so far (checked after the first 1 MiB).
+
+    --dereference, --no-dereference
+        Whether a symlink is archived as the file or directory it points to
+        (following symlinked directories, with loops detected and skipped) or
+        as a link, which is the default. Files with several hard links are
+        stored once; their other names become hard link entries. FIFOs and
+        character and block devices are archived as such; on extraction
+        FIFOs are always recreated but device nodes only when running as
+        root.
+
+    -S, --sparse
+        When creating, find the holes in files with SEEK_DATA/SEEK_HOLE and
+        store only the data, as GNU format sparse entries that GNU tar and
+        bsdtar understand. Sparse entries, these and the PAX 1.0 ones of GNU
+        tar --sparse --posix, are always extracted with their holes
+        recreated rather than written out as zeros. The older PAX 0.x sparse
+        formats are refused.
+
+    --listed-incremental <FILE>
+        When creating or adding, make an incremental backup against the
+        snapshot FILE, GNU tar style. If FILE does not exist this is a
+        level-0 backup of everything; the inode, mtime and size of every file
+        are then recorded in FILE. Later runs store only files whose
+        recorded state changed, plus every directory as a GNU dumpdir entry
+        listing what it holds, and update FILE. To make differential
+        backups, run each one against a copy of the level-0 snapshot. When
+        extracting, the same as --incremental.
+
+    -G, --incremental
+        When extracting, delete files that a directory's dumpdir entry shows
+        were gone when the archive was made. Extract a full backup and then
+        each incremental in order to restore the latest state.
+
+    --restore-at <TIME>
+        Restore a tree as of TIME from several archives of it, given with
+        -i oldest first (a full backup and later incremental or partial
+        ones). For every path the version with the newest modification time
+        not after TIME is extracted from whichever archive holds it, and
+        paths that a chosen --listed-incremental directory listing shows as
+        deleted are left out or removed. TIME is UTC: YYYY-MM-DD,
+        "YYYY-MM-DD HH:MM[:SS]" or @SECONDS.
+
+    --reproducible
+        When creating, make the archive depend only on the file names and
+        contents: entries are sorted by name, owners are stored as 0/0 with
+        no names, permissions as 0644 or 0755, and no atime or ctime,
+        extended attributes, ACLs or SELinux context is written.
+        Modification times are clamped to SOURCE_DATE_EPOCH when it is set
+        and fixed to a constant otherwise. Gzip output never carries a file
+        name, timestamp or OS, so the same inputs give the same bytes on any
+        machine (for a given codec, level and --threads 1 or not).
+
+    --passphrase-file <FILE>
+        Encrypt the archive with the passphrase on the first line of FILE
+        when creating, and decrypt with it when reading. Archives are
+        encrypted after compression in the age format (age-encryption.org),
+        so the age tool can decrypt them too: scrypt derives the key and
+        every 64 KiB of data is authenticated with ChaCha20-Poly1305.
+        Encrypted archives are recognised by their header; a wrong
+        passphrase or key, or data that was damaged or tampered with, makes
+        the operation fail rather than yield altered files.
+
+    -r, --recipient <KEY>
+        Encrypt the archive to an age X25519 public key (age1...) instead of
+        a passphrase (repeatable; any one of the keys can decrypt). Key
+        pairs come from age-keygen.
+
+    --identity <FILE>
+        Decrypt with the secret keys in an age identity FILE (repeatable).
+        With --add an encrypted archive is read with --passphrase-file or
+        --identity and written again for --recipient or --passphrase-file.
+
+    --split-size <SIZE>
+        When creating, write the archive as a series of parts of at most
+        SIZE bytes (K, M, G or T suffixes) named OUTPUT.000, OUTPUT.001 and
+        so on, e.g. to fit file size limits. The compressed stream is cut
+        at the byte, so files straddle parts freely and `cat OUTPUT.*`
+        rebuilds the whole archive. To read a split archive give its first
+        part (or OUTPUT itself) to -i; the parts are joined back, and a
+        missing or truncated part is reported by its number. Split archives
+        cannot be appended to with --add. A --sign signature covers the
+        joined stream and is written to OUTPUT.sig.
+
+    --keygen
+        Generate an Ed25519 key pair for signing archives, writing the
+        secret key to OUTPUT.key (mode 0600) and the public key to
+        OUTPUT.pub. Keys are made from the system's random source and
+        existing files are never overwritten.
+
+    --sign <KEY>
+        When creating or adding, sign the archive with the secret KEY file
+        and write the detached signature to ARCHIVE.sig. What is signed is
+        the SHA-256 of the archive file exactly as written, so it covers
+        the compression and encryption too.
+
+    --verify-signature <PUBKEY>
+        Before extracting, listing, verifying, comparing or restoring, check
+        ARCHIVE.sig against the archive and the public key file PUBKEY, and
+        refuse the archive unless the signature is good. The archive is
+        read once to check it and then extracted from the same open file.
+
+    -p, --preserve-permissions
+        When extracting, restore permissions exactly as archived, including
+        the setuid, setgid and sticky bits. Without it those bits are dropped
+        and the umask is applied. Default for root unless --umask is given.
+
+    --same-owner, --no-same-owner
+        Whether extraction restores the archived owner and group. Owner and
+        group names are looked up through the system's user and group
+        database (NSS, so LDAP and sssd accounts count) and win over the
+        stored ids. On by default for root; when it cannot be done a
+        warning is printed and extraction continues.
+
+    --numeric-owner
+        Store, restore and list owners and groups by numeric id only,
+        ignoring names.
+
+    --umask <MASK>
+        Octal umask applied to extracted permissions instead of the
+        process umask (implies no --preserve-permissions unless given).
+
+    --xattrs, --acls, --selinux
+        Extended attributes (user.*, trusted.*, security.*), POSIX ACLs and
+        SELinux contexts are always recorded when creating, as PAX headers
+        in the SCHILY.xattr.*, SCHILY.acl.* and RHT.security.selinux form
+        that GNU tar and bsdtar use. These flags restore them on extraction
+        to files and directories; attributes that cannot be set are reported
+        and skipped.
+
     -i, --input <INPUT>
         Input file(s) for compression, or archive file for decompression.
-        Accepts multiple files when compressing.
+        Accepts multiple files when compressing. When extracting or listing,
+        names after the archive select members to process: a name selects
+        that entry and everything below it, and names containing *, ? or [
+        are wildcard patterns. Each name must match something in the archive.
+        An archive named - is read from standard input.
 
     -o, --output <OUTPUT>
-        Output archive file (.tar.gz) or extraction directory.
+        Output archive file (.tar.gz) or extraction directory. An archive
+        named - is written to standard output (never to a terminal). Status
+        messages always go to standard error, so piped archive data stays
+        clean; nothing reading or writing a pipe seeks, but --sign,
+        --split-size, --add, --verify-signature and --restore-at need real
+        files.
 
     -h, --help
         Display this help message.
@@ -94,11 +755,89 @@ EXAMPLES
     Compress files into an archive:
         tart -c -i file1.txt file2.txt -o archive.tar.gz
 
+    Compress files into a zstd archive:
+        tart -c --codec zstd -i src/ -o archive.tar.zst
+
+    Compress a backup with maximum ratio:
+        tart -c --codec xz --level 9e -i data/ -o backup.tar.xz
+
+    Compress a large tree on all cores:
+        tart -c --threads 0 -i build/ -o build.tar.gz
+
+    Archive a source tree without build output:
+        tart -c --exclude target/ --exclude '*.o' -i project/ -o project.tar.gz
+
+    Package a repository the way git sees it:
+        tart -c --respect-ignore -i repo/ -o repo.tar.gz
+
+    Encrypt a backup for an offsite key, then restore it:
+        tart -c -r age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p \
+            -i /srv/ -o srv.tar.gz.age
+        tart -d --identity offsite-key.txt -i srv.tar.gz.age -o /srv/
+
+    Ship a large backup over a link with a 2 GiB file limit, then list it:
+        tart -c --split-size 2G -i /data/ -o data.tar.zst --codec zstd
+        tart -t -i data.tar.zst.000
+
+    Sign release tarballs, and check them before unpacking downstream:
+        tart --keygen -o release
+        tart -c --sign release.key -i dist/ -o app-2.4.0.tar.gz
+        tart -d --verify-signature release.pub -i app-2.4.0.tar.gz -o /opt/app/
+
+    Copy a tree between machines without a temporary file:
+        ssh build-host tart -c --codec zstd -i /srv/app/ -o - | tart -d -i - -o /srv/
+
     Decompress an archive:
         tart -d -i archive.tar.gz -o extracted_dir/
 
-    Add a file to an existing archive:
-        tart -a -i newfile.txt -o archive.tar.gz"#,
+    Extract one config file and a subdirectory:
+        tart -d -i archive.tar.gz etc/app.conf 'logs/2024-*' -o restored/
+
+    Extract a release tarball without its top-level directory:
+        tart -d --strip-components 1 -i project-1.2.3.tar.gz -o project/
+
+    Store a tree under a different top-level name:
+        tart -c --transform 's,^build,release-1.0,' -i build/ -o release.tar.gz
+
+    Extract an untrusted upload with resource limits:
+        tart -d --max-total-size 2G --max-entries 100000 --max-ratio 200 \
+            -i upload.tar.gz -o /srv/uploads/1234/
+
+    Build a release tarball that hashes the same on every machine:
+        SOURCE_DATE_EPOCH=$(git log -1 --format=%ct) \
+            tart -c --reproducible -i project/ -o project-1.2.3.tar.gz
+
+    Nightly incremental backups, then a restore of the whole chain:
+        tart -c --listed-incremental home.snar -i /home/ -o home-0.tar.gz
+        tart -c --listed-incremental home.snar -i /home/ -o home-1.tar.gz
+        tart -d -G -i home-0.tar.gz -o /restore/
+        tart -d -G -i home-1.tar.gz -o /restore/
+
+    Restore the home directories as they were on the morning of May 3rd:
+        tart --restore-at "2024-05-03 06:00" \
+            -i home-0.tar.gz home-1.tar.gz home-2.tar.gz -o /restore/
+
+    Back up VM disk images without expanding their holes:
+        tart -c -S --codec zstd -i /var/lib/libvirt/images/ -o vms.tar.zst
+
+    Restore a system backup as root with the original ids:
+        tart -d -p --same-owner --numeric-owner -i rootfs.tar.gz -o /mnt/root/
+
+    Restore a server backup with its ACLs and extended attributes:
+        tart -d --xattrs --acls --selinux -i srv.tar.gz -o /srv/
+
+    List an archive in long format:
+        tart -t -v -i archive.tar.gz
+
+    Check a backup before relying on it:
+        tart -c --digest -i /home/ -o backup.tar.gz
+        tart --verify -i backup.tar.gz
+
+    Check that a deployed tree still matches the shipped tarball:
+        tart --diff -i app-2.4.0.tar.gz -o /opt/app/
+
+    Add files to an existing archive:
+        tart -a -i newfile.txt newdir/ -o archive.tar.gz"#,
     );
 }
 fn main() {
@@ -119,24 +858,255 @@ fn main() {
         .arg(Arg::new("add")
             .short('a')
             .long("add")
-            .help("Add a file to an existing .tar.gz archive")
+            .help("Append files to an existing .tar.gz archive")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("list")
+            .short('t')
+            .long("list")
+            .help("List the contents of a .tar.gz archive")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("verify")
+            .long("verify")
+            .help("Check an archive's checksums and stored digests")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("diff")
+            .long("diff")
+            .visible_alias("compare")
+            .help("Compare an archive with the files on disk")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Show a long listing with --list")
             .action(clap::ArgAction::SetTrue))
         .arg(Arg::new("help")
             .short('h')
             .long("help")
             .help("Display the help page")
             .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("codec")
+            .long("codec")
+            .help("Compression codec: gzip, bzip2, xz, zstd, lz4 or none")
+            .value_parser(CODEC_NAMES)
+            .num_args(1))
+        .arg(Arg::new("level")
+            .long("level")
+            .help("Compression level for the chosen codec (e.g. 0-9, 1-22, 9e)")
+            .value_parser(|s: &str| s.parse::<Level>())
+            .conflicts_with_all(["fast", "best"])
+            .num_args(1))
+        .arg(Arg::new("fast")
+            .long("fast")
+            .help("Use the codec's fastest compression level")
+            .conflicts_with("best")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("best")
+            .long("best")
+            .help("Use the codec's highest-ratio compression level")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("threads")
+            .long("threads")
+            .help("Compress gzip on N threads (0 = all cores)")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("1")
+            .num_args(1))
+        .arg(Arg::new("exclude")
+            .long("exclude")
+            .help("Exclude paths matching a glob pattern (repeatable)")
+            .action(clap::ArgAction::Append)
+            .num_args(1))
+        .arg(Arg::new("include")
+            .long("include")
+            .help("Only include paths matching a glob pattern (repeatable)")
+            .action(clap::ArgAction::Append)
+            .num_args(1))
+        .arg(Arg::new("respect-ignore")
+            .long("respect-ignore")
+            .help("Skip files ignored by .gitignore, .ignore and .tartignore")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("strip-components")
+            .long("strip-components")
+            .help("Drop N leading path components when extracting")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("0")
+            .num_args(1))
+        .arg(Arg::new("transform")
+            .long("transform")
+            .help("Rewrite member names with a sed-style s,regex,replacement, expression")
+            .value_parser(|s: &str| s.parse::<Transform>())
+            .action(clap::ArgAction::Append)
+            .num_args(1))
+        .arg(Arg::new("unsafe-paths")
+            .long("unsafe-paths")
+            .help("Allow .., absolute paths and escaping links when extracting")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("max-total-size")
+            .long("max-total-size")
+            .help("Abort extraction after writing more than SIZE bytes")
+            .value_parser(parse_size)
+            .num_args(1))
+        .arg(Arg::new("max-file-size")
+            .long("max-file-size")
+            .help("Abort extraction on a file larger than SIZE bytes")
+            .value_parser(parse_size)
+            .num_args(1))
+        .arg(Arg::new("max-entries")
+            .long("max-entries")
+            .help("Abort extraction after more than N entries")
+            .value_parser(clap::value_parser!(u64))
+            .num_args(1))
+        .arg(Arg::new("max-depth")
+            .long("max-depth")
+            .help("Abort extraction on paths deeper than N components")
+            .value_parser(clap::value_parser!(usize))
+            .num_args(1))
+        .arg(Arg::new("max-ratio")
+            .long("max-ratio")
+            .help("Abort extraction above this decompression ratio")
+            .value_parser(clap::value_parser!(f64))
+            .num_args(1))
+        .arg(Arg::new("dereference")
+            .long("dereference")
+            .help("Archive the files symlinks point to instead of the links")
+            .conflicts_with("no-dereference")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("no-dereference")
+            .long("no-dereference")
+            .help("Archive symlinks as links (default)")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("sparse")
+            .short('S')
+            .long("sparse")
+            .help("Store holes in sparse files instead of their zeros")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("listed-incremental")
+            .long("listed-incremental")
+            .help("Create incremental backups against a snapshot FILE")
+            .value_parser(clap::value_parser!(PathBuf))
+            .num_args(1))
+        .arg(Arg::new("incremental")
+            .short('G')
+            .long("incremental")
+            .help("Delete files that incremental archives record as removed")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("restore-at")
+            .long("restore-at")
+            .help("Restore the tree as of TIME from a series of archives")
+            .value_parser(parse_time)
+            .requires("input")
+            .requires("output")
+            .num_args(1))
+        .arg(Arg::new("digest")
+            .long("digest")
+            .help("Store each file's SHA-256 for --verify (reads every file twice)")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("reproducible")
+            .long("reproducible")
+            .help("Create byte-for-byte reproducible archives (honours SOURCE_DATE_EPOCH)")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("passphrase-file")
+            .long("passphrase-file")
+            .help("Encrypt or decrypt with the passphrase on the first line of FILE")
+            .value_parser(clap::value_parser!(PathBuf))
+            .num_args(1))
+        .arg(Arg::new("recipient")
+            .short('r')
+            .long("recipient")
+            .help("Encrypt to an age X25519 public key (repeatable)")
+            .value_parser(|s: &str| {
+                s.parse::<age::x25519::Recipient>().map_err(|e| format!("invalid recipient '{}': {}", s, e))
+            })
+            .action(clap::ArgAction::Append)
+            .num_args(1))
+        .arg(Arg::new("identity")
+            .long("identity")
+            .help("Decrypt with the secret keys in an age identity FILE (repeatable)")
+            .value_parser(clap::value_parser!(PathBuf))
+            .action(clap::ArgAction::Append)
+            .num_args(1))
+        .arg(Arg::new("split-size")
+            .long("split-size")
+            .help("Write the archive as numbered parts of at most SIZE bytes")
+            .value_parser(|s: &str| match parse_size(s) {
+                Ok(0) => Err("the split size must be at least one byte".to_string()),
+                other => other,
+            })
+            .num_args(1))
+        .arg(Arg::new("keygen")
+            .long("keygen")
+            .help("Generate an Ed25519 signing key pair as OUTPUT.key and OUTPUT.pub")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("sign")
+            .long("sign")
+            .help("Sign the archive with a secret KEY file, writing ARCHIVE.sig")
+            .value_parser(clap::value_parser!(PathBuf))
+            .num_args(1))
+        .arg(Arg::new("verify-signature")
+            .long("verify-signature")
+            .help("Refuse archives whose ARCHIVE.sig is not a valid signature by PUBKEY")
+            .value_parser(clap::value_parser!(PathBuf))
+            .num_args(1))
+        .arg(Arg::new("preserve-permissions")
+            .short('p')
+            .long("preserve-permissions")
+            .help("Restore exact permissions, including setuid/setgid/sticky bits")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("same-owner")
+            .long("same-owner")
+            .help("Restore file owners when extracting (default for root)")
+            .conflicts_with("no-same-owner")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("no-same-owner")
+            .long("no-same-owner")
+            .help("Extract files as the current user (default for other users)")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("numeric-owner")
+            .long("numeric-owner")
+            .help("Use numeric user and group ids instead of names")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("umask")
+            .long("umask")
+            .help("Octal umask applied to extracted permissions")
+            .value_parser(|s: &str| match u32::from_str_radix(s, 8) {
+                Ok(mask) if mask <= 0o777 => Ok(mask),
+                _ => Err(format!("invalid umask '{}'", s)),
+            })
+            .num_args(1))
+        .arg(Arg::new("xattrs")
+            .long("xattrs")
+            .help("Restore user, trusted and security extended attributes")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("acls")
+            .long("acls")
+            .help("Restore POSIX ACLs")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("selinux")
+            .long("selinux")
+            .help("Restore SELinux contexts")
+            .action(clap::ArgAction::SetTrue))
         .arg(Arg::new("input")
             .short('i')
             .long("input")
             .help("Input files (for compression) or archive (for decompression)")
-            .required(false)
+            .required_if_eq_any([
+                ("compress", "true"),
+                ("decompress", "true"),
+                ("add", "true"),
+                ("list", "true"),
+                ("verify", "true"),
+                ("diff", "true"),
+            ])
             .num_args(1..))
         .arg(Arg::new("output")
             .short('o')
             .long("output")
             .help("Output archive file (.tar.gz) or extraction directory")
-            .required(false)
+            .required_if_eq_any([
+                ("compress", "true"),
+                ("decompress", "true"),
+                ("add", "true"),
+                ("keygen", "true"),
+            ])
             .num_args(1))
         .get_matches();
 
@@ -144,20 +1114,153 @@ fn main() {
             display_help();
             return;
         }
-    let output = matches.get_one::<String>("output").unwrap().as_str();
+    let codec: Option<Codec> = matches.get_one::<String>("codec").map(|s| s.parse().unwrap());
+    let level = if matches.get_flag("fast") {
+        Level::Fastest
+    } else if matches.get_flag("best") {
+        Level::Best
+    } else {
+        matches.get_one::<Level>("level").copied().unwrap_or(Level::Default)
+    };
+    let threads = match *matches.get_one::<usize>("threads").unwrap() {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let patterns = |id: &str| -> Vec<&str> {
+        matches.get_many::<String>(id).map_or(Vec::new(), |v| v.map(|s| s.as_str()).collect())
+    };
+    let filter = or_exit(Filter::new(&patterns("include"), &patterns("exclude")), "Invalid pattern");
+    let transforms: Vec<Transform> =
+        matches.get_many::<Transform>("transform").map_or(Vec::new(), |v| v.cloned().collect());
+    // As in GNU tar, root restores owners and exact permissions by default
+    // and other users get files they own with the umask applied.
+    let is_root = dest::is_root();
+    let numeric_owner = matches.get_flag("numeric-owner");
+    let umask = matches.get_one::<u32>("umask").copied();
+    let listed_incremental = matches.get_one::<PathBuf>("listed-incremental");
+    let restore_at = matches.get_one::<u64>("restore-at").copied();
+    let passphrase = matches
+        .get_one::<PathBuf>("passphrase-file")
+        .map(|path| or_exit(crypt::read_passphrase(path), "Cannot read passphrase"));
+    let recipients: Vec<age::x25519::Recipient> =
+        matches.get_many("recipient").map_or(Vec::new(), |v| v.cloned().collect());
+    let identities = matches.get_many::<PathBuf>("identity").map_or(Vec::new(), |paths| {
+        paths.flat_map(|path| or_exit(crypt::read_identities(path), "Cannot read identity")).collect()
+    });
+    let sign_key = matches
+        .get_one::<PathBuf>("sign")
+        .map(|path| or_exit(sign::read_secret_key(path), "Cannot read signing key"));
+    let trusted_key = matches
+        .get_one::<PathBuf>("verify-signature")
+        .map(|path| or_exit(sign::read_public_key(path), "Cannot read public key"));
+    let encryption = if !recipients.is_empty() {
+        Encryption::Recipients(recipients)
+    } else if let Some(passphrase) = &passphrase {
+        Encryption::Passphrase(passphrase.clone())
+    } else {
+        Encryption::None
+    };
+    let extract_options = ExtractOptions {
+        codec,
+        filter: filter.clone(),
+        strip_components: *matches.get_one::<usize>("strip-components").unwrap(),
+        transforms: transforms.clone(),
+        unsafe_paths: matches.get_flag("unsafe-paths"),
+        limits: Limits {
+            max_total_size: matches.get_one::<u64>("max-total-size").copied(),
+            max_entries: matches.get_one::<u64>("max-entries").copied(),
+            max_file_size: matches.get_one::<u64>("max-file-size").copied(),
+            max_depth: matches.get_one::<usize>("max-depth").copied(),
+            max_ratio: matches.get_one::<f64>("max-ratio").copied(),
+        },
+        preserve_permissions: matches.get_flag("preserve-permissions") || (is_root && umask.is_none()),
+        same_owner: if matches.get_flag("same-owner") {
+            true
+        } else {
+            is_root && !matches.get_flag("no-same-owner")
+        },
+        numeric_owner,
+        attrs: xattrs::Restore {
+            xattrs: matches.get_flag("xattrs"),
+            acls: matches.get_flag("acls"),
+            selinux: matches.get_flag("selinux"),
+        },
+        incremental: matches.get_flag("incremental") || listed_incremental.is_some() || restore_at.is_some(),
+        keys: Keys { passphrase, identities },
+        trusted_key,
+        umask: match umask {
+            Some(mask) => {
+                dest::set_umask(mask);
+                mask
+            }
+            None => dest::current_umask(),
+        },
+    };
+    let reproducible = matches.get_flag("reproducible");
+    let source_date_epoch = if reproducible {
+        or_exit(source_date_epoch(), "Invalid SOURCE_DATE_EPOCH")
+    } else {
+        None
+    };
+    let create_options = CreateOptions {
+        codec,
+        level,
+        threads,
+        filter,
+        respect_ignore: matches.get_flag("respect-ignore"),
+        transforms,
+        numeric_owner,
+        dereference: matches.get_flag("dereference"),
+        sparse: matches.get_flag("sparse"),
+        digest: matches.get_flag("digest"),
+        reproducible,
+        source_date_epoch,
+        listed_incremental: listed_incremental.cloned(),
+        encryption,
+        sign_key,
+        split_size: matches.get_one::<u64>("split-size").copied(),
+    };
+
+    if matches.get_flag("list") {
+        let inputs: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
+        or_exit(
+            ignore_closed_stdout(list_archive(inputs[0], &inputs[1..], matches.get_flag("verbose"), &extract_options)),
+            "Listing failed",
+        );
+        return;
+    }
+    if matches.get_flag("verify") {
+        let inputs: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
+        or_exit(ignore_closed_stdout(verify_archive(inputs[0], &extract_options)), "Verification failed");
+        return;
+    }
+    if matches.get_flag("diff") {
+        let inputs: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
+        let base = matches.get_one::<String>("output").map_or(".", |s| s.as_str());
+        or_exit(ignore_closed_stdout(diff_archive(inputs[0], &inputs[1..], base, &extract_options)), "Comparison failed");
+        return;
+    }
+    // Every mode below has clap require -o; with none of them there is
+    // nothing to write to.
+    let output = matches.get_one::<String>("output").map_or("", |s| s.as_str());
 
-    if matches.get_flag("compress") {
+    if matches.get_flag("keygen") {
+        let (secret, public) = or_exit(sign::generate(Path::new(output)), "Key generation failed");
+        eprintln!("✅ Wrote secret key {} and public key {}", secret.display(), public.display());
+    } else if matches.get_flag("compress") {
         let input_files: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
-        compress_files(&input_files, output).expect("Compression failed");
+        or_exit(compress_files(&input_files, output, &create_options), "Compression failed");
+    } else if let Some(at) = restore_at {
+        let inputs: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
+        or_exit(restore_archives(&inputs, at, output, &extract_options), "Restore failed");
     } else if matches.get_flag("decompress") {
-        let input = matches.get_one::<String>("input").unwrap().as_str();
-        decompress_files(input, output).expect("Decompression failed");
+        let inputs: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
+        or_exit(decompress_files(inputs[0], &inputs[1..], output, &extract_options), "Decompression failed");
     }
     else if matches.get_flag("add") {
-        let input = matches.get_one::<String>("input").unwrap().as_str();
-        let file = matches.get_one::<String>("output").unwrap().as_str();
-        add_file_to_tar_gz(input, file).expect("Adding file failed");
+        let input_files: Vec<_> = matches.get_many::<String>("input").unwrap().map(|s| s.as_str()).collect();
+        or_exit(add_files_to_archive(output, &input_files, &create_options, &extract_options.keys), "Adding files failed");
     } else {
-        eprintln!("❌ Please specify --compress or --decompress");
+        eprintln!("❌ Please specify --compress, --decompress, --restore-at, --list, --add or --keygen");
     }
 }
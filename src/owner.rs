@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::hash::Hash;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+// Name service entries are small; this only bounds a buffer that keeps
+// growing on ERANGE, e.g. for a group with a huge member list.
+const MAX_BUFFER: usize = 1 << 20;
+
+/// User and group names looked up through the system's name service with
+/// `getpwuid`, `getpwnam`, `getgrgid` and `getgrnam`, as GNU tar does, so
+/// users and groups from LDAP or sssd resolve as well as local ones. Every
+/// answer, a miss included, is kept for the rest of the run, as archives
+/// tend to name the same few owners over and over.
+#[derive(Default)]
+pub struct Owners {
+    user_names: RefCell<HashMap<u32, Option<String>>>,
+    group_names: RefCell<HashMap<u32, Option<String>>>,
+    uids: RefCell<HashMap<String, Option<u32>>>,
+    gids: RefCell<HashMap<String, Option<u32>>>,
+}
+
+impl Owners {
+    pub fn new() -> Owners {
+        Owners::default()
+    }
+
+    pub fn user_name(&self, uid: u32) -> Option<String> {
+        cached(&self.user_names, uid, || {
+            lookup::<libc::passwd>(|entry, buf, len, result| unsafe { libc::getpwuid_r(uid, entry, buf, len, result) })
+                .map(|(name, _)| name)
+        })
+    }
+
+    pub fn group_name(&self, gid: u32) -> Option<String> {
+        cached(&self.group_names, gid, || {
+            lookup::<libc::group>(|entry, buf, len, result| unsafe { libc::getgrgid_r(gid, entry, buf, len, result) })
+                .map(|(name, _)| name)
+        })
+    }
+
+    pub fn uid(&self, name: &str) -> Option<u32> {
+        cached(&self.uids, name.to_string(), || {
+            let name = CString::new(name).ok()?;
+            lookup::<libc::passwd>(|entry, buf, len, result| unsafe {
+                libc::getpwnam_r(name.as_ptr(), entry, buf, len, result)
+            })
+            .map(|(_, id)| id)
+        })
+    }
+
+    pub fn gid(&self, name: &str) -> Option<u32> {
+        cached(&self.gids, name.to_string(), || {
+            let name = CString::new(name).ok()?;
+            lookup::<libc::group>(|entry, buf, len, result| unsafe {
+                libc::getgrnam_r(name.as_ptr(), entry, buf, len, result)
+            })
+            .map(|(_, id)| id)
+        })
+    }
+}
+
+fn cached<K: Hash + Eq, V: Clone>(cache: &RefCell<HashMap<K, V>>, key: K, look_up: impl FnOnce() -> V) -> V {
+    if let Some(value) = cache.borrow().get(&key) {
+        return value.clone();
+    }
+    let value = look_up();
+    cache.borrow_mut().insert(key, value.clone());
+    value
+}
+
+// What is read from a `passwd` or `group` entry: its name and id.
+trait NssEntry {
+    fn name(&self) -> *const c_char;
+    fn id(&self) -> u32;
+}
+
+impl NssEntry for libc::passwd {
+    fn name(&self) -> *const c_char {
+        self.pw_name
+    }
+
+    fn id(&self) -> u32 {
+        self.pw_uid
+    }
+}
+
+impl NssEntry for libc::group {
+    fn name(&self) -> *const c_char {
+        self.gr_name
+    }
+
+    fn id(&self) -> u32 {
+        self.gr_gid
+    }
+}
+
+// Calls one of the reentrant `get*_r` functions with a buffer for the
+// entry's strings, retrying with a larger one while it reports ERANGE.
+// Names that are not UTF-8 count as not found, as headers hold UTF-8.
+fn lookup<E: NssEntry>(call: impl Fn(*mut E, *mut c_char, usize, *mut *mut E) -> c_int) -> Option<(String, u32)> {
+    let mut buf: Vec<c_char> = vec![0; 1024];
+    loop {
+        let mut entry = MaybeUninit::<E>::uninit();
+        let mut result = ptr::null_mut();
+        match call(entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) {
+            libc::ERANGE if buf.len() < MAX_BUFFER => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => {
+                // The entry is filled in and its strings point into `buf`,
+                // which outlives this.
+                let entry = unsafe { entry.assume_init() };
+                let name = unsafe { CStr::from_ptr(entry.name()) }.to_str().ok()?;
+                return Some((name.to_string(), entry.id()));
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_resolves_both_ways_and_misses_are_none() {
+        let owners = Owners::new();
+        assert_eq!(owners.user_name(0).as_deref(), Some("root"));
+        assert_eq!(owners.uid("root"), Some(0));
+        assert_eq!(owners.gid(&owners.group_name(0).unwrap()), Some(0));
+        assert_eq!(owners.uid("no-such-user-tart"), None);
+        assert_eq!(owners.uid("bad\0name"), None);
+        // Answered from the cache the second time.
+        assert_eq!(owners.uid("no-such-user-tart"), None);
+        assert_eq!(owners.user_name(0).as_deref(), Some("root"));
+    }
+}
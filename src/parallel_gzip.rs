@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use flate2::Compression;
+
+use crate::codec::gzip_header;
+
+const BLOCK_SIZE: usize = 1 << 20;
+
+type Job = (Vec<u8>, Sender<io::Result<Vec<u8>>>);
+
+/// A pigz-style gzip writer. Input is cut into fixed-size blocks that are
+/// compressed on a pool of worker threads, each into its own gzip member, and
+/// the members are written out in input order. The concatenation is a valid
+/// multi-member gzip file that any gzip reader can decompress.
+pub struct ParallelGzEncoder<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    pending: VecDeque<Receiver<io::Result<Vec<u8>>>>,
+    max_pending: usize,
+    wrote_member: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(inner: W, level: Compression, threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    let Ok((block, done)) = job else { break };
+                    let _ = done.send(compress_block(&block, level));
+                })
+            })
+            .collect();
+
+        ParallelGzEncoder {
+            inner,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            jobs: Some(jobs),
+            workers,
+            pending: VecDeque::new(),
+            max_pending: threads * 2,
+            wrote_member: false,
+        }
+    }
+
+    /// Compresses any buffered input, writes every outstanding member and
+    /// returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() || !self.wrote_member {
+            self.submit()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_next()?;
+        }
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        let block = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        let (done, result) = mpsc::channel();
+        self.jobs
+            .as_ref()
+            .expect("encoder already finished")
+            .send((block, done))
+            .map_err(|_| io::Error::other("compression worker exited"))?;
+        self.pending.push_back(result);
+        self.wrote_member = true;
+
+        while self.pending.len() > self.max_pending {
+            self.write_next()?;
+        }
+        Ok(())
+    }
+
+    fn write_next(&mut self) -> io::Result<()> {
+        if let Some(result) = self.pending.pop_front() {
+            let member = result
+                .recv()
+                .map_err(|_| io::Error::other("compression worker exited"))??;
+            self.inner.write_all(&member)?;
+        }
+        Ok(())
+    }
+}
+
+fn compress_block(block: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = gzip_header().write(Vec::with_capacity(block.len() / 2), level);
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.submit()?;
+        }
+        Ok(n)
+    }
+
+    // Blocks are only handed to workers when full; flushing does not cut a
+    // short member, it only flushes what has already been written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,356 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+const BLOCK: usize = 512;
+
+/// Splits PAX extended header data into key/value records using each
+/// record's length prefix, so values may hold arbitrary bytes, newlines
+/// included. Parsing stops at the first malformed record.
+pub fn parse(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(space) = rest.iter().position(|&b| b == b' ') else {
+            break;
+        };
+        let Some(len) = std::str::from_utf8(&rest[..space]).ok().and_then(|n| n.parse::<usize>().ok()) else {
+            break;
+        };
+        if len <= space + 1 || len > rest.len() || rest[len - 1] != b'\n' {
+            break;
+        }
+        let record = &rest[space + 1..len - 1];
+        if let Some(eq) = record.iter().position(|&b| b == b'=') {
+            if let Ok(key) = std::str::from_utf8(&record[..eq]) {
+                records.push((key.to_string(), record[eq + 1..].to_vec()));
+            }
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// A reader placed in front of `tar::Archive` that follows the tar block
+/// structure as the archive is read and keeps the data of every local PAX
+/// header, keyed by the position of the header it applies to. The tar
+/// crate's own PAX parser splits records at newlines, which breaks binary
+/// values such as xattrs.
+pub struct PaxTap<R> {
+    inner: R,
+    state: Rc<RefCell<TapState>>,
+}
+
+/// The other end of a [`PaxTap`], used to look up an entry's records.
+pub struct PaxRecords(Rc<RefCell<TapState>>);
+
+#[derive(Default)]
+struct TapState {
+    block: Vec<u8>,
+    // Bytes of member data and padding still to pass before the next header,
+    // and how many of them belong to a PAX header being captured.
+    remaining: u64,
+    capture: u64,
+    captured: Vec<u8>,
+    // Old GNU sparse headers may be followed by extension blocks before the
+    // data; `sparse_size` is the data size to skip once they end.
+    sparse_extended: bool,
+    sparse_size: u64,
+    pending: Option<Vec<u8>>,
+    position: u64,
+    records: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<R> PaxTap<R> {
+    pub fn new(inner: R) -> (PaxTap<R>, PaxRecords) {
+        let state = Rc::new(RefCell::new(TapState::default()));
+        (PaxTap { inner, state: Rc::clone(&state) }, PaxRecords(state))
+    }
+}
+
+impl<R: Read> Read for PaxTap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.state.borrow_mut().feed(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl PaxRecords {
+    /// The PAX records for the entry whose header starts at
+    /// `header_position`. Records of earlier, skipped entries are dropped.
+    pub fn take(&self, header_position: u64) -> Vec<(String, Vec<u8>)> {
+        let mut state = self.0.borrow_mut();
+        let later = state.records.split_off(&(header_position + 1));
+        let data = state.records.remove(&header_position);
+        state.records = later;
+        data.map(|data| parse(&data)).unwrap_or_default()
+    }
+}
+
+/// Copies the entries of the tar stream `reader` to `out` block for block,
+/// stopping at the end-of-archive blocks so that more entries can follow.
+/// Headers, GNU long names, PAX records and sparse extension blocks all go
+/// across unchanged. The tar crate's raw mode cannot be used for this: it
+/// steps over an entry by its size field, which leaves out the extension
+/// blocks of an old GNU sparse file with more than four regions.
+pub fn copy_entries(mut reader: impl Read, out: &mut impl Write) -> io::Result<()> {
+    let mut state = TapState::default();
+    let mut block = [0u8; BLOCK];
+    loop {
+        let at_header = state.remaining == 0 && !state.sparse_extended;
+        if !read_block(&mut reader, &mut block, at_header)? {
+            return Ok(());
+        }
+        if at_header {
+            if block.iter().all(|&b| b == 0) {
+                return Ok(());
+            }
+            check_header(&block)?;
+        }
+        state.feed(&block)?;
+        // Only the block structure matters here, not the records.
+        state.records.clear();
+        out.write_all(&block)?;
+    }
+}
+
+// Fills `block`, returning false at a clean end of the stream, which is
+// only allowed where a header would start.
+fn read_block(reader: &mut impl Read, block: &mut [u8; BLOCK], at_header: bool) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < BLOCK {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 && at_header => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the archive ends in the middle of an entry")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+// The header checksum: the sum of the block's bytes with the checksum field
+// itself counted as spaces.
+fn check_header(block: &[u8; BLOCK]) -> io::Result<()> {
+    let sum = block[..148].iter().chain(&block[156..]).map(|&b| b as u64).sum::<u64>() + 8 * b' ' as u64;
+    if parse_size(&block[148..156]).ok() != Some(sum) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "archive header checksum mismatch"));
+    }
+    Ok(())
+}
+
+impl TapState {
+    fn feed(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            if self.remaining > 0 {
+                let n = (self.remaining.min(buf.len() as u64)) as usize;
+                let keep = (self.capture.min(n as u64)) as usize;
+                self.captured.extend_from_slice(&buf[..keep]);
+                self.capture -= keep as u64;
+                self.remaining -= n as u64;
+                if self.remaining == 0 && !self.captured.is_empty() {
+                    self.pending = Some(std::mem::take(&mut self.captured));
+                }
+                self.position += n as u64;
+                buf = &buf[n..];
+                continue;
+            }
+            let n = (BLOCK - self.block.len()).min(buf.len());
+            self.block.extend_from_slice(&buf[..n]);
+            self.position += n as u64;
+            buf = &buf[n..];
+            if self.block.len() == BLOCK {
+                let block = std::mem::take(&mut self.block);
+                self.header(&block, self.position - BLOCK as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, block: &[u8], start: u64) -> io::Result<()> {
+        if self.sparse_extended {
+            self.sparse_extended = block[504] != 0;
+            if !self.sparse_extended {
+                self.remaining = padded(self.sparse_size)?;
+            }
+            return Ok(());
+        }
+        if block.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let mut size = parse_size(&block[124..136])?;
+        match block[156] {
+            b'x' => {
+                self.capture = size;
+                self.captured.clear();
+            }
+            b'g' | b'L' | b'K' => {}
+            kind => {
+                if let Some(data) = self.pending.take() {
+                    if let Some(pax_size) = parse(&data)
+                        .iter()
+                        .find(|(key, _)| key == "size")
+                        .and_then(|(_, value)| std::str::from_utf8(value).ok()?.parse().ok())
+                    {
+                        size = pax_size;
+                    }
+                    self.records.insert(start, data);
+                }
+                if kind == b'S' && block[482] != 0 {
+                    self.sparse_extended = true;
+                    self.sparse_size = size;
+                    return Ok(());
+                }
+            }
+        }
+        self.remaining = padded(size)?;
+        Ok(())
+    }
+}
+
+fn padded(size: u64) -> io::Result<u64> {
+    size.div_ceil(BLOCK as u64)
+        .checked_mul(BLOCK as u64)
+        .ok_or_else(bad_size)
+}
+
+// Header numbers are octal, or big-endian base-256 when the high bit of the
+// first byte is set (GNU tar's encoding for values that do not fit). A
+// negative base-256 number, or one past the largest file size, `off_t`'s
+// maximum, cannot come from a real entry.
+fn parse_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        if field[0] & 0x40 != 0 {
+            return Err(bad_size());
+        }
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x3f), |n, &b| {
+                n.checked_mul(256).map(|n| n | u64::from(b))
+            })
+            .filter(|&n| n <= i64::MAX as u64)
+            .ok_or_else(bad_size);
+    }
+    let digits = std::str::from_utf8(field).unwrap_or("");
+    Ok(u64::from_str_radix(digits.trim_matches(|c| c == ' ' || c == '\0'), 8).unwrap_or(0))
+}
+
+fn bad_size() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "archive header with an impossible entry size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse::{self, Region};
+    use tar::{Archive, Builder, Header};
+
+    // An old GNU sparse entry with six regions and a hole at the end, more
+    // than fit in its header, so it needs an extension block ahead of the data.
+    fn sparse_archive() -> (Vec<u8>, Vec<u8>) {
+        let mut regions: Vec<Region> = (0..6).map(|i| Region { offset: i * 4096, len: 1024 }).collect();
+        regions.push(Region { offset: 6 * 4096, len: 0 });
+        let mut contents = vec![0u8; 6 * 4096];
+        for region in &regions {
+            contents[region.offset as usize..(region.offset + region.len) as usize].fill(b'a' + (region.offset / 4096) as u8);
+        }
+        let mut header = Header::new_gnu();
+        header.set_path("many.img").unwrap();
+        header.set_mode(0o644);
+        let extensions = sparse::prepare_header(&mut header, &regions, contents.len() as u64);
+        assert!(!extensions.is_empty());
+        let mut data = extensions;
+        for region in &regions {
+            data.extend_from_slice(&contents[region.offset as usize..(region.offset + region.len) as usize]);
+        }
+        let mut tar = Builder::new(Vec::new());
+        tar.append_pax_extensions([("TART.sha256", b"0123".as_slice())]).unwrap();
+        tar.append_data(&mut header, "many.img", data.as_slice()).unwrap();
+        (tar.into_inner().unwrap(), contents)
+    }
+
+    #[test]
+    fn copy_entries_keeps_sparse_extension_blocks() {
+        let (archive, contents) = sparse_archive();
+        let mut out = Vec::new();
+        copy_entries(archive.as_slice(), &mut out).unwrap();
+        let mut tar = Builder::new(out);
+        let mut header = Header::new_gnu();
+        header.set_size(4);
+        tar.append_data(&mut header, "new.txt", b"new\n".as_slice()).unwrap();
+        let appended = tar.into_inner().unwrap();
+
+        let mut archive = Archive::new(appended.as_slice());
+        let mut entries = archive.entries().unwrap();
+        let mut sparse = entries.next().unwrap().unwrap();
+        assert_eq!(sparse.path().unwrap().to_str(), Some("many.img"));
+        let mut extracted = Vec::new();
+        sparse.read_to_end(&mut extracted).unwrap();
+        assert!(extracted == contents);
+        drop(sparse);
+        let mut added = entries.next().unwrap().unwrap();
+        assert_eq!(added.path().unwrap().to_str(), Some("new.txt"));
+        let mut text = String::new();
+        added.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "new\n");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn copy_entries_stops_at_the_end_marker_and_checks_headers() {
+        let (mut archive, _) = sparse_archive();
+        let mut out = Vec::new();
+        copy_entries(archive.as_slice(), &mut out).unwrap();
+        // The two zero blocks that end the archive are left for the builder.
+        assert!(out.len() < archive.len());
+        assert!(out[out.len() - BLOCK..].iter().any(|&b| b != 0));
+
+        archive[0] ^= 1;
+        let err = copy_entries(archive.as_slice(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn with_size_field(size: [u8; 12]) -> Vec<u8> {
+        let mut header = Header::new_gnu();
+        header.set_path("bomb").unwrap();
+        header.as_old_mut().size = size;
+        header.set_cksum();
+        let mut archive = header.as_bytes().to_vec();
+        archive.resize(4 * BLOCK, 0);
+        archive
+    }
+
+    #[test]
+    fn impossible_entry_sizes_are_an_error() {
+        let mut huge = [0xff; 12];
+        huge[0] = 0x80;
+        huge[1..4].fill(0);
+        let mut pax_huge = Builder::new(Vec::new());
+        pax_huge
+            .append_pax_extensions([("size", u64::MAX.to_string().as_bytes())])
+            .unwrap();
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        pax_huge.append_data(&mut header, "bomb", io::empty()).unwrap();
+
+        for archive in [with_size_field(huge), with_size_field([0xff; 12]), pax_huge.into_inner().unwrap()] {
+            let (mut tap, _) = PaxTap::new(archive.as_slice());
+            let err = io::copy(&mut tap, &mut io::sink()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = copy_entries(archive.as_slice(), &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // The largest size a file can have still parses.
+        let mut largest = [0xff; 12];
+        largest[0] = 0x80;
+        largest[1..4].fill(0);
+        largest[4] = 0x7f;
+        let archive = with_size_field(largest);
+        let (mut tap, _) = PaxTap::new(&archive[..BLOCK]);
+        io::copy(&mut tap, &mut io::sink()).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use tar::Archive;
+
+use crate::extract;
+use crate::incremental;
+
+/// Works out what a point-in-time restore extracts from a series of
+/// archives of the same tree. Versions are told apart by modification time:
+/// for each path the entry with the newest mtime not after the target time
+/// wins, and a later archive wins a tie. Paths whose every version is newer
+/// are left out, as they did not exist yet or their content then is lost.
+///
+/// A directory whose chosen version is a dumpdir (see --listed-incremental)
+/// had exactly the listed children at that time, so anything else below it
+/// is dropped as deleted.
+pub struct Plan {
+    at: u64,
+    chosen: HashMap<PathBuf, Version>,
+}
+
+struct Version {
+    mtime: u64,
+    archive: usize,
+    index: usize,
+    listing: Option<HashSet<OsString>>,
+}
+
+impl Plan {
+    pub fn new(at: u64) -> Plan {
+        Plan {
+            at,
+            chosen: HashMap::new(),
+        }
+    }
+
+    /// Reads the entries of archive number `archive`. Archives must be
+    /// scanned in order, oldest first.
+    pub fn scan<R: Read>(&mut self, archive: usize, reader: R) -> io::Result<()> {
+        let mut tar = Archive::new(reader);
+        for (index, entry) in tar.entries()?.enumerate() {
+            let mut entry = entry?;
+            let path = extract::normalize(&entry.path()?);
+            let mtime = entry.header().mtime()?;
+            if mtime > self.at || path.as_os_str().is_empty() {
+                continue;
+            }
+            if self.chosen.get(&path).is_some_and(|v| v.mtime > mtime) {
+                continue;
+            }
+            let listing = if incremental::is_dumpdir(entry.header().entry_type()) {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                Some(incremental::listed_names(&data).into_iter().collect())
+            } else {
+                None
+            };
+            self.chosen.insert(path, Version { mtime, archive, index, listing });
+        }
+        Ok(())
+    }
+
+    /// The entry indices to extract from each of the first `archives`
+    /// archives.
+    pub fn finish(self, archives: usize) -> Vec<HashSet<usize>> {
+        let mut picks = vec![HashSet::new(); archives];
+        for (path, version) in &self.chosen {
+            if !self.is_deleted(path) {
+                picks[version.archive].insert(version.index);
+            }
+        }
+        picks
+    }
+
+    // Whether a dumpdir chosen for one of the ancestors of `path` leaves out
+    // the next component on the way down.
+    fn is_deleted(&self, path: &Path) -> bool {
+        let mut child = path;
+        for ancestor in path.ancestors().skip(1) {
+            let listing = self.chosen.get(ancestor).and_then(|v| v.listing.as_ref());
+            if let (Some(listing), Some(name)) = (listing, child.file_name()) {
+                if !listing.contains(name) {
+                    return true;
+                }
+            }
+            child = ancestor;
+        }
+        false
+    }
+}
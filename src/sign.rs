@@ -0,0 +1,178 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::verify;
+
+const SECRET_KEY_TAG: &str = "tart-ed25519-secret-key";
+const PUBLIC_KEY_TAG: &str = "tart-ed25519-public-key";
+const SIGNATURE_TAG: &str = "tart-ed25519-signature";
+
+// What is signed is this prefix followed by the SHA-256 of the archive file,
+// so a tart signature cannot be passed off as one over anything else.
+const SIGNED_PREFIX: &[u8] = b"tart-ed25519-signature\0";
+
+/// The detached signature of `archive`: `archive.sig` next to it.
+pub fn signature_path(archive: &Path) -> PathBuf {
+    with_suffix(archive, ".sig")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// How a public key is shown in messages and key files.
+pub fn key_id(key: &VerifyingKey) -> String {
+    verify::to_hex(key.as_bytes())
+}
+
+/// Makes a new key pair from the operating system's random source and
+/// writes it to `base.key`, readable by the owner only, and `base.pub`.
+/// Existing files are never overwritten.
+pub fn generate(base: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    let mut seed = [0u8; 32];
+    OsRng.fill_bytes(&mut seed);
+    let key = SigningKey::from_bytes(&seed);
+
+    let secret_path = with_suffix(base, ".key");
+    let public_path = with_suffix(base, ".pub");
+    for path in [&secret_path, &public_path] {
+        if fs::symlink_metadata(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+    }
+    let mut secret = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&secret_path)?;
+    writeln!(secret, "{} {}", SECRET_KEY_TAG, verify::to_hex(&seed))?;
+    secret.sync_all()?;
+    let mut public = OpenOptions::new().write(true).create_new(true).open(&public_path)?;
+    writeln!(public, "{} {}", PUBLIC_KEY_TAG, key_id(&key.verifying_key()))?;
+    Ok((secret_path, public_path))
+}
+
+pub fn read_secret_key(path: &Path) -> io::Result<SigningKey> {
+    let seed: [u8; 32] = read_tagged(path, SECRET_KEY_TAG)?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn read_public_key(path: &Path) -> io::Result<VerifyingKey> {
+    let bytes: [u8; 32] = read_tagged(path, PUBLIC_KEY_TAG)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| invalid(path, "is not a valid Ed25519 public key"))
+}
+
+/// Writes the signature file for an archive whose contents hash to
+/// `digest`. Besides the signature it names the key and the digest, so a
+/// failed check can say which of the two did not match.
+pub fn write_signature(path: &Path, key: &SigningKey, digest: &[u8; 32]) -> io::Result<()> {
+    let signature = key.sign(&signed_message(digest));
+    let mut file = File::create(path)?;
+    writeln!(file, "{} {}", SIGNATURE_TAG, verify::to_hex(&signature.to_bytes()))?;
+    writeln!(file, "{} {}", PUBLIC_KEY_TAG, key_id(&key.verifying_key()))?;
+    writeln!(file, "sha256 {}", verify::to_hex(digest))?;
+    file.sync_all()
+}
+
+/// Reads `archive` to its end and checks it against the signature at
+/// `sig_path` and `key`. Callers rewind and read the archive from the same
+/// handles, so what is extracted is what was checked even if the path is
+/// replaced meanwhile.
+pub fn check(archive: impl Read, sig_path: &Path, key: &VerifyingKey) -> io::Result<()> {
+    let signature: [u8; 64] = read_tagged(sig_path, SIGNATURE_TAG).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no signature: {} does not exist", sig_path.display()),
+        ),
+        _ => e,
+    })?;
+    if let Ok(signer) = read_tagged::<32>(sig_path, PUBLIC_KEY_TAG) {
+        if signer != *key.as_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("signed by key {}, not by {}", verify::to_hex(&signer), key_id(key)),
+            ));
+        }
+    }
+    let (digest, _) = verify::sha256(archive)?;
+    key.verify(&signed_message(&digest), &Signature::from_bytes(&signature)).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "BAD signature: the archive was modified after signing or the signature is forged",
+        )
+    })
+}
+
+fn signed_message(digest: &[u8; 32]) -> Vec<u8> {
+    [SIGNED_PREFIX, digest].concat()
+}
+
+// Finds the `TAG <hex>` line of a key or signature file and decodes it.
+fn read_tagged<const N: usize>(path: &Path, tag: &str) -> io::Result<[u8; N]> {
+    let text = fs::read_to_string(path)?;
+    let value = text
+        .lines()
+        .find_map(|line| line.strip_prefix(tag)?.strip_prefix(' '))
+        .ok_or_else(|| invalid(path, &format!("has no {} line", tag)))?;
+    from_hex(value.trim()).ok_or_else(|| invalid(path, &format!("has a malformed {} line", tag)))
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn invalid(path: &Path, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} {}", path.display(), what))
+}
+
+/// Hashes what is written through it, for --sign to sign the archive's
+/// bytes as they go to disk. Without a key it only passes them on.
+pub struct SignWriter<'a, W: Write> {
+    inner: W,
+    signer: Option<(&'a SigningKey, Sha256)>,
+}
+
+impl<'a, W: Write> SignWriter<'a, W> {
+    pub fn new(inner: W, key: Option<&'a SigningKey>) -> Self {
+        SignWriter {
+            inner,
+            signer: key.map(|key| (key, Sha256::new())),
+        }
+    }
+
+    /// Returns the inner writer, and the key and the digest of everything
+    /// written when signing.
+    pub fn finish(self) -> (W, Option<(&'a SigningKey, [u8; 32])>) {
+        let signed = self.signer.map(|(key, hasher)| (key, hasher.finalize().into()));
+        (self.inner, signed)
+    }
+}
+
+impl<W: Write> Write for SignWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some((_, hasher)) = &mut self.signer {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,410 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+use rustix::fs::{self as rfs, SeekFrom};
+use rustix::io::Errno;
+use tar::{EntryType, GnuExtSparseHeader, Header};
+
+// Zero runs shorter than this are written out rather than left as holes on
+// extraction; it matches the block size of common file systems.
+const HOLE_GRANULE: usize = 4096;
+
+/// A run of data in a sparse file, with everything between runs a hole.
+pub struct Region {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Finds the data regions of `file` with `SEEK_DATA`/`SEEK_HOLE`. Returns
+/// `None` when the file has no holes or the file system cannot tell. The
+/// file offset is back at the start afterwards.
+pub fn data_regions(file: &File, size: u64) -> io::Result<Option<Vec<Region>>> {
+    let regions = find_regions(file, size);
+    rfs::seek(file, SeekFrom::Start(0))?;
+    regions
+}
+
+fn find_regions(file: &File, size: u64) -> io::Result<Option<Vec<Region>>> {
+    let mut regions = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let start = match rfs::seek(file, SeekFrom::Data(pos)) {
+            Ok(start) => start,
+            // Nothing but a hole up to the end of the file.
+            Err(Errno::NXIO) => break,
+            Err(Errno::INVAL) | Err(Errno::OPNOTSUPP) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let end = rfs::seek(file, SeekFrom::Hole(start))?.min(size);
+        regions.push(Region { offset: start, len: end - start });
+        pos = end;
+    }
+    if let [only] = regions.as_slice() {
+        if only.offset == 0 && only.len == size {
+            return Ok(None);
+        }
+    }
+    // GNU tar ends the map with an empty region at the real size when the
+    // file ends in a hole, so readers know how long it is.
+    if regions.last().is_none_or(|last| last.offset + last.len < size) {
+        regions.push(Region { offset: size, len: 0 });
+    }
+    Ok(Some(regions))
+}
+
+/// Turns `header` into an old GNU format sparse header for `regions` and
+/// returns the extension headers that must follow it for regions beyond the
+/// four that fit in the header itself. GNU tar and bsdtar read this format.
+pub fn prepare_header(header: &mut Header, regions: &[Region], real_size: u64) -> Vec<u8> {
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(regions.iter().map(|r| r.len).sum());
+    let gnu = header.as_gnu_mut().expect("sparse entries need a GNU header");
+    gnu.set_real_size(real_size);
+    for (region, slot) in regions.iter().zip(gnu.sparse.iter_mut()) {
+        slot.set_offset(region.offset);
+        slot.set_length(region.len);
+    }
+    let mut rest = regions.iter().skip(gnu.sparse.len()).peekable();
+    gnu.set_is_extended(rest.peek().is_some());
+
+    let mut extensions = Vec::new();
+    while rest.peek().is_some() {
+        let mut ext = GnuExtSparseHeader::new();
+        for slot in ext.sparse.iter_mut() {
+            let Some(region) = rest.next() else {
+                break;
+            };
+            slot.set_offset(region.offset);
+            slot.set_length(region.len);
+        }
+        ext.set_is_extended(rest.peek().is_some());
+        extensions.extend_from_slice(ext.as_bytes());
+    }
+    extensions
+}
+
+/// Reads the data regions of a sparse file back to back, which is how they
+/// are stored in the archive.
+pub struct RegionReader<'a> {
+    file: &'a File,
+    regions: Vec<Region>,
+    index: usize,
+    done: u64,
+}
+
+impl<'a> RegionReader<'a> {
+    pub fn new(file: &'a File, regions: Vec<Region>) -> Self {
+        RegionReader { file, regions, index: 0, done: 0 }
+    }
+}
+
+impl Read for RegionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(region) = self.regions.get(self.index) {
+            let left = region.len - self.done;
+            if left == 0 {
+                self.index += 1;
+                self.done = 0;
+                continue;
+            }
+            let want = left.min(buf.len() as u64) as usize;
+            let n = self.file.read_at(&mut buf[..want], region.offset + self.done)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being archived"));
+            }
+            self.done += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+/// A file stored in the PAX 1.0 sparse format that GNU tar uses with
+/// --posix: a regular entry named `GNUSparseFile.<pid>/NAME`, whose PAX
+/// records give the real name and size and whose data starts with the map
+/// of its regions; see [`PaxSparseReader`].
+pub struct PaxSparse {
+    pub name: PathBuf,
+    pub real_size: u64,
+}
+
+impl PaxSparse {
+    /// Recognises the format from an entry's PAX records. The older 0.0 and
+    /// 0.1 versions, which keep the map in the records, are refused rather
+    /// than extracted as their packed data.
+    pub fn from_records(records: &[(String, Vec<u8>)]) -> io::Result<Option<PaxSparse>> {
+        let value = |key: &str| {
+            records
+                .iter()
+                .find(|(k, _)| k == key)
+                .and_then(|(_, value)| std::str::from_utf8(value).ok())
+        };
+        let version = (value("GNU.sparse.major"), value("GNU.sparse.minor"));
+        if version == (None, None) {
+            if records.iter().any(|(key, _)| key.starts_with("GNU.sparse.")) {
+                return Err(unsupported("0.x"));
+            }
+            return Ok(None);
+        }
+        if version != (Some("1"), Some("0")) {
+            return Err(unsupported(&format!("{}.{}", version.0.unwrap_or("?"), version.1.unwrap_or("?"))));
+        }
+        let name = value("GNU.sparse.name");
+        let real_size = value("GNU.sparse.realsize").and_then(|size| size.parse().ok());
+        match (name, real_size) {
+            (Some(name), Some(real_size)) => Ok(Some(PaxSparse { name: PathBuf::from(name), real_size })),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "PAX sparse entry without GNU.sparse.name or GNU.sparse.realsize",
+            )),
+        }
+    }
+}
+
+fn unsupported(version: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the PAX {} sparse format is not supported; only 1.0, GNU tar's default, is", version),
+    )
+}
+
+/// Reads the whole file back out of the data of a PAX 1.0 sparse entry:
+/// the map at its start, a line with the number of regions followed by a
+/// line for each region's offset and one for its length, padded to a
+/// block, then the regions back to back. Holes come out as zeros, for
+/// [`HoleWriter`] to skip again.
+pub struct PaxSparseReader<R> {
+    inner: R,
+    regions: Vec<Region>,
+    index: usize,
+    pos: u64,
+    real_size: u64,
+}
+
+impl<R: Read> PaxSparseReader<R> {
+    pub fn new(mut inner: R, real_size: u64) -> io::Result<PaxSparseReader<R>> {
+        let mut consumed = 0;
+        let count = read_number(&mut inner, &mut consumed)?;
+        let mut regions = Vec::new();
+        let mut end = 0;
+        for _ in 0..count {
+            let offset = read_number(&mut inner, &mut consumed)?;
+            let len = read_number(&mut inner, &mut consumed)?;
+            if offset < end || offset.checked_add(len).is_none_or(|region_end| region_end > real_size) {
+                return Err(bad_map("regions out of order or past the end of the file"));
+            }
+            end = offset + len;
+            regions.push(Region { offset, len });
+        }
+        let padding = (512 - consumed % 512) % 512;
+        io::copy(&mut (&mut inner).take(padding), &mut io::sink())?;
+        Ok(PaxSparseReader { inner, regions, index: 0, pos: 0, real_size })
+    }
+}
+
+// Reads a decimal number ending in a newline, counting the bytes read.
+fn read_number(reader: &mut impl Read, consumed: &mut u64) -> io::Result<u64> {
+    let mut digits = String::new();
+    let mut byte = [0u8];
+    loop {
+        reader.read_exact(&mut byte)?;
+        *consumed += 1;
+        match byte[0] {
+            b'\n' => break,
+            b @ b'0'..=b'9' if digits.len() < 20 => digits.push(b as char),
+            _ => return Err(bad_map("malformed number")),
+        }
+    }
+    digits.parse().map_err(|_| bad_map("malformed number"))
+}
+
+fn bad_map(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad PAX sparse map: {}", what))
+}
+
+impl<R: Read> Read for PaxSparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (next, in_data) = loop {
+            match self.regions.get(self.index) {
+                Some(region) if self.pos < region.offset => break (region.offset, false),
+                Some(region) if self.pos < region.offset + region.len => break (region.offset + region.len, true),
+                Some(_) => self.index += 1,
+                None => break (self.real_size, false),
+            }
+        };
+        let want = (next - self.pos).min(buf.len() as u64) as usize;
+        let n = if in_data {
+            let n = self.inner.read(&mut buf[..want])?;
+            if n == 0 && want > 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sparse entry data ends early"));
+            }
+            n
+        } else {
+            buf[..want].fill(0);
+            want
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes extracted data to a file, leaving zero-filled blocks as holes.
+/// [`finish`](HoleWriter::finish) sets the final length, which also creates
+/// a trailing hole.
+pub struct HoleWriter<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> HoleWriter<'a> {
+    pub fn new(file: &'a File) -> Self {
+        HoleWriter { file, pos: 0 }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.file.set_len(self.pos)
+    }
+}
+
+impl Write for HoleWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Keep chunks aligned to file offsets so holes line up with blocks.
+        let to_boundary = HOLE_GRANULE - (self.pos % HOLE_GRANULE as u64) as usize;
+        let chunk = &buf[..buf.len().min(to_boundary)];
+        if chunk.len() < HOLE_GRANULE || chunk.iter().any(|&b| b != 0) {
+            self.file.write_all_at(chunk, self.pos)?;
+        }
+        self.pos += chunk.len() as u64;
+        Ok(chunk.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{self, tests::create_options, CreateOptions};
+    use crate::extract::tests::{extract_all, options};
+    use crate::pax;
+    use std::fs;
+    use std::path::Path;
+    use tar::{Archive, Builder};
+
+    // A 1 MiB file with a 4 KiB run of data every 128 KiB, more regions
+    // than fit in an old GNU header.
+    fn write_sparse_file(path: &Path) -> Vec<u8> {
+        let file = File::create(path).unwrap();
+        file.set_len(1 << 20).unwrap();
+        for i in 0..8u8 {
+            file.write_all_at(&[i + 1; 4096], u64::from(i) * (128 << 10)).unwrap();
+        }
+        fs::read(path).unwrap()
+    }
+
+    fn archive(inputs: &[&Path], tar: &mut Builder<Vec<u8>>) {
+        let inputs: Vec<&str> = inputs.iter().map(|path| path.to_str().unwrap()).collect();
+        let options = CreateOptions { sparse: true, ..create_options() };
+        create::append_inputs(tar, &inputs, &options, None).unwrap();
+    }
+
+    fn extracted(dst: &Path, src: &Path) -> Vec<u8> {
+        fs::read(dst.join(src.strip_prefix("/").unwrap())).unwrap()
+    }
+
+    #[test]
+    fn sparse_files_survive_create_extract_and_add() {
+        let src = tempfile::tempdir().unwrap();
+        let first = src.path().join("first.img");
+        let second = src.path().join("second.img");
+        let first_data = write_sparse_file(&first);
+        let second_data = write_sparse_file(&second);
+
+        let mut tar = Builder::new(Vec::new());
+        archive(&[&first], &mut tar);
+        let created = tar.into_inner().unwrap();
+        let has_holes = data_regions(&File::open(&first).unwrap(), 1 << 20).unwrap().is_some();
+        if has_holes {
+            let mut archive = Archive::new(created.as_slice());
+            let entry = archive.entries().unwrap().next().unwrap().unwrap();
+            assert_eq!(entry.header().entry_type(), EntryType::GNUSparse);
+        }
+
+        let dst = tempfile::tempdir().unwrap();
+        extract_all(&created, dst.path(), &options()).unwrap();
+        assert_eq!(extracted(dst.path(), &first), first_data);
+
+        // What --add does: copy the entries over, then append after them.
+        let mut copied = Vec::new();
+        pax::copy_entries(created.as_slice(), &mut copied).unwrap();
+        let mut tar = Builder::new(copied);
+        archive(&[&second], &mut tar);
+        let added = tar.into_inner().unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        extract_all(&added, dst.path(), &options()).unwrap();
+        assert_eq!(extracted(dst.path(), &first), first_data);
+        assert_eq!(extracted(dst.path(), &second), second_data);
+    }
+
+    fn append_pax_sparse(tar: &mut Builder<Vec<u8>>, records: &[(&str, &str)], map: &str, data: &[u8]) {
+        tar.append_pax_extensions(records.iter().map(|(key, value)| (*key, value.as_bytes())))
+            .unwrap();
+        let mut contents = map.as_bytes().to_vec();
+        contents.resize(512, 0);
+        contents.extend_from_slice(data);
+        let mut header = Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(contents.len() as u64);
+        tar.append_data(&mut header, "GNUSparseFile.1/real.img", contents.as_slice())
+            .unwrap();
+    }
+
+    #[test]
+    fn pax_1_0_sparse_entries_extract_under_their_real_name() {
+        let mut tar = Builder::new(Vec::new());
+        let records = [
+            ("GNU.sparse.major", "1"),
+            ("GNU.sparse.minor", "0"),
+            ("GNU.sparse.name", "real.img"),
+            ("GNU.sparse.realsize", "20000"),
+        ];
+        append_pax_sparse(&mut tar, &records, "2\n0\n5\n10000\n3\n", b"helloabc");
+        tar.finish().unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        extract_all(&tar.into_inner().unwrap(), dst.path(), &options()).unwrap();
+        let mut expected = vec![0; 20000];
+        expected[..5].copy_from_slice(b"hello");
+        expected[10000..10003].copy_from_slice(b"abc");
+        assert_eq!(fs::read(dst.path().join("real.img")).unwrap(), expected);
+        assert!(!dst.path().join("GNUSparseFile.1").exists());
+    }
+
+    #[test]
+    fn other_pax_sparse_versions_and_bad_maps_are_refused() {
+        let old = [("GNU.sparse.size", "20000"), ("GNU.sparse.map", "0,5")];
+        let bad_map = [
+            ("GNU.sparse.major", "1"),
+            ("GNU.sparse.minor", "0"),
+            ("GNU.sparse.name", "real.img"),
+            ("GNU.sparse.realsize", "100"),
+        ];
+        for (records, map, message) in [
+            (&old[..], "", "the PAX 0.x sparse format is not supported"),
+            (&bad_map[..], "1\n90\n20\n", "bad PAX sparse map"),
+            (&bad_map[..], "1\nx\n", "bad PAX sparse map"),
+        ] {
+            let mut tar = Builder::new(Vec::new());
+            append_pax_sparse(&mut tar, records, map, b"");
+            tar.finish().unwrap();
+            let dst = tempfile::tempdir().unwrap();
+            let err = extract_all(&tar.into_inner().unwrap(), dst.path(), &options()).unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use regex::bytes::{Captures, Regex, RegexBuilder};
+
+/// A sed-style `s/regex/replacement/flags` rewrite of archive member names,
+/// as taken by `--transform`. Any character may stand in for `/` as the
+/// delimiter. As in GNU tar the regex is POSIX basic syntax (`\(...\)` for
+/// groups) unless the `x` flag selects extended syntax. The replacement
+/// understands `&` for the whole match and `\1` to `\9` for groups; the other
+/// flags are `g` (replace every match) and `i` (ignore case). Names are
+/// matched as bytes, so parts of a name that are not UTF-8 come through
+/// unchanged.
+#[derive(Clone)]
+pub struct Transform {
+    regex: Regex,
+    replacement: String,
+    global: bool,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid transform expression '{}'", s);
+        let rest = s.strip_prefix('s').ok_or_else(invalid)?;
+        let delim = rest.chars().next().ok_or_else(invalid)?;
+        let parts = split_unescaped(&rest[delim.len_utf8()..], delim);
+        let [pattern, replacement, flags] =
+            <[String; 3]>::try_from(parts).map_err(|_| invalid())?;
+
+        let mut global = false;
+        let mut ignore_case = false;
+        let mut extended = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => ignore_case = true,
+                'x' => extended = true,
+                _ => return Err(format!("unknown transform flag '{}' in '{}'", flag, s)),
+            }
+        }
+        let pattern = if extended {
+            pattern
+        } else {
+            basic_to_extended(&pattern)
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| format!("{}: {}", invalid(), e))?;
+        Ok(Transform {
+            regex,
+            replacement,
+            global,
+        })
+    }
+}
+
+// Splits on `delim` except where it is escaped with a backslash; an escaped
+// delimiter loses its backslash, other escapes are kept for the regex or the
+// replacement to interpret.
+fn split_unescaped(s: &str, delim: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delim => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delim => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+    parts
+}
+
+// Rewrites a POSIX basic regex into the extended syntax the regex crate
+// parses: `\(`, `\)`, `\{`, `\}`, `\|`, `\+` and `\?` become operators and
+// their bare forms become literals. Bracket expressions are copied as-is.
+fn basic_to_extended(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(op @ ('(' | ')' | '{' | '}' | '|' | '+' | '?')) => out.push(op),
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push_str("\\\\"),
+            },
+            '(' | ')' | '{' | '}' | '|' | '+' | '?' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '[' => {
+                out.push('[');
+                // A `]` right after `[` or `[^` is a literal member.
+                if chars.peek() == Some(&'^') {
+                    out.push(chars.next().unwrap());
+                }
+                if chars.peek() == Some(&']') {
+                    out.push_str("\\]");
+                    chars.next();
+                }
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl Transform {
+    pub fn apply(&self, name: &[u8]) -> Vec<u8> {
+        let expand = |caps: &Captures| {
+            let mut out = Vec::new();
+            let mut chars = self.replacement.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '&' => out.extend_from_slice(&caps[0]),
+                    '\\' => match chars.next() {
+                        Some(d @ '0'..='9') => {
+                            let group = d.to_digit(10).unwrap() as usize;
+                            out.extend_from_slice(
+                                caps.get(group).map_or(&[][..], |m| m.as_bytes()),
+                            );
+                        }
+                        Some(other) => push_char(&mut out, other),
+                        None => out.push(b'\\'),
+                    },
+                    c => push_char(&mut out, c),
+                }
+            }
+            out
+        };
+        if self.global {
+            self.regex.replace_all(name, expand).into_owned()
+        } else {
+            self.regex.replace(name, expand).into_owned()
+        }
+    }
+}
+
+/// Runs every transform over `path` in order.
+pub fn apply_all(transforms: &[Transform], path: &Path) -> PathBuf {
+    if transforms.is_empty() {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().as_bytes().to_vec();
+    for transform in transforms {
+        name = transform.apply(&name);
+    }
+    PathBuf::from(OsStr::from_bytes(&name))
+}
+
+fn push_char(out: &mut Vec<u8>, c: char) {
+    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn transform(name: &[u8], expr: &str) -> Vec<u8> {
+        let transforms = [expr.parse::<Transform>().unwrap()];
+        apply_all(&transforms, Path::new(OsStr::from_bytes(name)))
+            .into_os_string()
+            .into_vec()
+    }
+
+    #[test]
+    fn names_that_are_not_utf8_keep_their_bytes() {
+        assert_eq!(
+            transform(b"src/caf\xe9.txt", "s,^src/,out/,"),
+            b"out/caf\xe9.txt"
+        );
+        assert_eq!(
+            transform(b"a\xff/b\xff", "s/\\([ab]\\)/\\1\\1/g"),
+            b"aa\xff/bb\xff"
+        );
+        assert_eq!(transform(b"\xfe\xfe", "s/x/y/g"), b"\xfe\xfe");
+    }
+
+    #[test]
+    fn replacements_expand_groups_and_the_match() {
+        assert_eq!(
+            transform(b"Docs/Readme.MD", "s/\\.md$/.txt/i"),
+            b"Docs/Readme.txt"
+        );
+        assert_eq!(transform(b"a-b-c", "s/-/_/g"), b"a_b_c");
+        assert_eq!(transform(b"v1.2", "s/[0-9]/<&>/g"), b"v<1>.<2>");
+        assert_eq!(transform("naïve".as_bytes(), "s/ï/i/"), b"naive");
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+use tar::Archive;
+
+use crate::pax::PaxTap;
+
+/// The PAX keyword tart stores each regular file's SHA-256 under, as
+/// lowercase hex of the file's full contents.
+pub const SHA256_RECORD: &str = "TART.sha256";
+
+/// Counts of a --verify run.
+pub struct Report {
+    pub passed: usize,
+    pub failed: usize,
+    pub unchecked: usize,
+}
+
+/// Reads the whole archive from `reader`, printing one tab-separated
+/// `STATUS path detail` line per entry: `PASS` when its data matches the
+/// stored digest (or it has no data), `FAIL` when it does not, and `NODIGEST`
+/// for a file archived without one. Header checksums are checked by the tar
+/// reader and the codec's own checksums once the stream is read to its end;
+/// either failing ends the run with an error, after a `FAIL` line.
+pub fn verify<R: Read>(reader: R) -> io::Result<Report> {
+    let (tap, pax) = PaxTap::new(reader);
+    let mut archive = Archive::new(tap);
+    let mut out = io::stdout().lock();
+    let mut report = Report {
+        passed: 0,
+        failed: 0,
+        unchecked: 0,
+    };
+    for entry in archive.entries()? {
+        let mut entry = entry.map_err(|e| fail(&mut out, "-", e))?;
+        let path = entry.path()?.display().to_string();
+        let records = pax.take(entry.raw_header_position());
+        let expected = records
+            .iter()
+            .find(|(key, _)| key == SHA256_RECORD)
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned());
+        let entry_type = entry.header().entry_type();
+        if !(entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse()) {
+            writeln!(out, "PASS\t{}\theader", path)?;
+            report.passed += 1;
+            continue;
+        }
+        let (digest, _) = sha256(&mut entry).map_err(|e| fail(&mut out, &path, e))?;
+        let actual = to_hex(&digest);
+        match expected {
+            Some(expected) if expected == actual => {
+                writeln!(out, "PASS\t{}\tsha256 {}", path, actual)?;
+                report.passed += 1;
+            }
+            Some(expected) => {
+                writeln!(out, "FAIL\t{}\tsha256 {} does not match stored {}", path, actual, expected)?;
+                report.failed += 1;
+            }
+            None => {
+                writeln!(out, "NODIGEST\t{}\tsha256 {}", path, actual)?;
+                report.unchecked += 1;
+            }
+        }
+    }
+    // Past the end-of-archive blocks, so the decompressor reaches its
+    // trailer and checks it.
+    io::copy(&mut archive.into_inner(), &mut io::sink()).map_err(|e| fail(&mut out, "-", e))?;
+    Ok(report)
+}
+
+// Prints the FAIL line for an error that ends the run and hands it back.
+fn fail(out: &mut impl Write, path: &str, e: io::Error) -> io::Error {
+    let _ = writeln!(out, "FAIL\t{}\t{}", path, e);
+    e
+}
+
+/// The SHA-256 of everything `reader` yields, with its length.
+pub fn sha256(mut reader: impl Read) -> io::Result<([u8; 32], u64)> {
+    let mut hasher = Sha256::new();
+    let len = io::copy(&mut reader, &mut hasher)?;
+    Ok((hasher.finalize().into(), len))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes what passes through it, to check a file did not change between
+/// computing its digest and archiving it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
@@ -0,0 +1,300 @@
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Seek, Write};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The archive name that stands for standard input or output.
+pub const STDIO: &str = "-";
+
+// Standard input or output as a `File` of its own, so archive data goes
+// straight to the descriptor rather than through the line-buffered
+// `io::Stdout`. Nothing reading or writing it may seek.
+fn stdio_file(fd: BorrowedFd) -> io::Result<File> {
+    Ok(File::from(fd.try_clone_to_owned()?))
+}
+
+/// The name of part `index` of a split archive: `archive.tar.gz.000`,
+/// `archive.tar.gz.001` and so on.
+pub fn part_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+fn describe(base: &Path, index: usize) -> String {
+    format!("volume {} ({})", index + 1, part_path(base, index).display())
+}
+
+/// Where --compress writes the archive: one file, or with --split-size a
+/// series of parts of at most that many bytes, cut anywhere in the stream,
+/// so that `cat archive.tar.gz.*` gives back the whole archive.
+///
+/// The last part is always shorter than the size, with an empty one added
+/// when the archive ends exactly on a boundary, so a reader can tell a set
+/// that is complete from one whose last parts are missing.
+///
+/// An archive named [`STDIO`] goes to standard output, which must not be a
+/// terminal, and cannot be split.
+pub struct VolumeWriter {
+    base: PathBuf,
+    split: Option<u64>,
+    index: usize,
+    written: u64,
+    current: File,
+    to_stdout: bool,
+}
+
+impl VolumeWriter {
+    pub fn create(base: &Path, split: Option<u64>) -> io::Result<VolumeWriter> {
+        let to_stdout = base.as_os_str() == STDIO;
+        let current = if to_stdout {
+            if split.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--split-size needs an output file name to number the parts after",
+                ));
+            }
+            if io::stdout().is_terminal() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "refusing to write archive data to a terminal",
+                ));
+            }
+            stdio_file(io::stdout().as_fd())?
+        } else {
+            match split {
+                Some(_) => File::create(part_path(base, 0))?,
+                None => File::create(base)?,
+            }
+        };
+        Ok(VolumeWriter {
+            base: base.to_path_buf(),
+            split,
+            index: 0,
+            written: 0,
+            current,
+            to_stdout,
+        })
+    }
+
+    fn next_part(&mut self) -> io::Result<()> {
+        self.current.sync_all()?;
+        self.index += 1;
+        self.written = 0;
+        self.current = File::create(part_path(&self.base, self.index))?;
+        Ok(())
+    }
+
+    /// Closes the last part and returns how many were written. Parts with
+    /// higher numbers left over from an earlier, longer archive of the same
+    /// name are removed, as they would otherwise be read as part of it.
+    pub fn finish(mut self) -> io::Result<usize> {
+        if let Some(split) = self.split {
+            if self.written == split {
+                self.next_part()?;
+            }
+            let mut stale = self.index + 1;
+            while fs::symlink_metadata(part_path(&self.base, stale)).is_ok() {
+                fs::remove_file(part_path(&self.base, stale))?;
+                stale += 1;
+            }
+        }
+        if !self.to_stdout {
+            self.current.sync_all()?;
+        }
+        Ok(self.index + 1)
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(split) = self.split else {
+            return self.current.write(buf);
+        };
+        if self.written == split {
+            self.next_part()?;
+        }
+        let room = (split - self.written).min(buf.len() as u64) as usize;
+        let n = self.current.write(&buf[..room])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.flush()
+    }
+}
+
+/// Reads an archive given by its file name or, for a split archive, by its
+/// first part (or by the name without the part number), joining the parts
+/// back into one stream.
+///
+/// The parts are all opened and checked up front: a gap in the numbering
+/// or a part shorter than the others is reported by number before anything
+/// is read. A set that ends on a full-size part is missing its last ones.
+/// [`STDIO`] reads the archive from standard input.
+pub struct VolumeReader {
+    base: PathBuf,
+    parts: Vec<File>,
+    current: usize,
+    split: bool,
+    exhausted: Rc<Cell<bool>>,
+}
+
+impl VolumeReader {
+    pub fn open(path: &Path) -> io::Result<VolumeReader> {
+        let base = match path.to_str().and_then(|p| p.strip_suffix(".000")) {
+            Some(base) => PathBuf::from(base),
+            None if !path.exists() && part_path(path, 0).exists() => path.to_path_buf(),
+            None => {
+                let file = if path.as_os_str() == STDIO {
+                    stdio_file(io::stdin().as_fd())?
+                } else {
+                    File::open(path)?
+                };
+                return Ok(VolumeReader {
+                    base: path.to_path_buf(),
+                    parts: vec![file],
+                    current: 0,
+                    split: false,
+                    exhausted: Rc::new(Cell::new(false)),
+                })
+            }
+        };
+
+        let mut parts = Vec::new();
+        loop {
+            match File::open(part_path(&base, parts.len())) {
+                Ok(part) => parts.push(part),
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !parts.is_empty() => break,
+                Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", describe(&base, parts.len()), e))),
+            }
+        }
+        if let Some(later) = highest_part(&base)?.filter(|&later| later >= parts.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is missing (parts up to {:03} exist)", describe(&base, parts.len()), later),
+            ));
+        }
+        let sizes = parts.iter().map(|part| part.metadata().map(|m| m.len())).collect::<io::Result<Vec<_>>>()?;
+        let full = sizes.iter().copied().max().unwrap_or(0);
+        if let Some(short) = sizes[..sizes.len() - 1].iter().position(|&size| size < full) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is truncated: {} of {} bytes", describe(&base, short), sizes[short], full),
+            ));
+        }
+        if sizes.len() > 1 && sizes[sizes.len() - 1] == full {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is missing; the set ends on a full volume", describe(&base, parts.len())),
+            ));
+        }
+        Ok(VolumeReader {
+            base,
+            parts,
+            current: 0,
+            split: true,
+            exhausted: Rc::new(Cell::new(false)),
+        })
+    }
+
+    /// The archive's name without any part number, e.g. to find its
+    /// detached signature.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Goes back to the start of the first part.
+    pub fn rewind(&mut self) -> io::Result<()> {
+        for part in &mut self.parts {
+            part.rewind()?;
+        }
+        self.current = 0;
+        self.exhausted.set(false);
+        Ok(())
+    }
+
+    /// What to wrap the reader decoding this archive in so that its errors
+    /// name the last part when every part was read to its end before them:
+    /// a truncated last volume only shows as the stream ending too early.
+    pub fn end_check(&self) -> EndCheck {
+        EndCheck {
+            exhausted: Rc::clone(&self.exhausted),
+            last: self.split.then(|| describe(&self.base, self.parts.len() - 1)),
+        }
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.get_mut(self.current) {
+            let n = part.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.current += 1;
+        }
+        self.exhausted.set(true);
+        Ok(0)
+    }
+}
+
+// The highest part number of `base` in its directory, if any.
+fn highest_part(base: &Path) -> io::Result<Option<usize>> {
+    let (Some(dir), Some(name)) = (base.parent(), base.file_name().and_then(|n| n.to_str())) else {
+        return Ok(None);
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let prefix = format!("{}.", name);
+    let mut highest = None;
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let number = file_name.to_str().and_then(|n| n.strip_prefix(&prefix));
+        if let Some(index) = number.filter(|n| n.len() >= 3 && n.bytes().all(|b| b.is_ascii_digit())).and_then(|n| n.parse::<usize>().ok()) {
+            highest = highest.max(Some(index));
+        }
+    }
+    Ok(highest)
+}
+
+/// See [`VolumeReader::end_check`].
+pub struct EndCheck {
+    exhausted: Rc<Cell<bool>>,
+    last: Option<String>,
+}
+
+impl EndCheck {
+    pub fn wrap<'a>(self, reader: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match self.last {
+            Some(last) => Box::new(Explained {
+                inner: reader,
+                exhausted: self.exhausted,
+                last,
+            }),
+            None => reader,
+        }
+    }
+}
+
+struct Explained<R> {
+    inner: R,
+    exhausted: Rc<Cell<bool>>,
+    last: String,
+}
+
+impl<R: Read> Read for Explained<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            if !self.exhausted.get() {
+                return e;
+            }
+            io::Error::new(
+                e.kind(),
+                format!("{} (the archive ends early: {} is truncated or later volumes are missing)", e, self.last),
+            )
+        })
+    }
+}
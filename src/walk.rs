@@ -0,0 +1,358 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+// Per-directory ignore files, lowest precedence first: a later file overrides
+// an earlier one in the same directory, and a deeper directory overrides its
+// parents, as git does for nested .gitignore files.
+const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".tartignore"];
+
+/// `--include`/`--exclude` patterns, matched against archive-relative paths
+/// the way GNU tar does: a pattern without a leading `/` may match at any
+/// depth, `*` does not cross `/`, and a trailing `/` only matches directories.
+/// An excluded directory is not descended into.
+#[derive(Clone)]
+pub struct Filter {
+    include: PatternSet,
+    exclude: PatternSet,
+}
+
+#[derive(Clone)]
+struct PatternSet {
+    any: GlobSet,
+    dirs: GlobSet,
+    len: usize,
+}
+
+impl PatternSet {
+    fn new(patterns: &[&str]) -> io::Result<PatternSet> {
+        let mut any = GlobSetBuilder::new();
+        let mut dirs = GlobSetBuilder::new();
+        for pattern in patterns {
+            let (pattern, dir_only) = match pattern.strip_suffix('/') {
+                Some(p) => (p, true),
+                None => (*pattern, false),
+            };
+            let pattern = pattern.trim_start_matches("./");
+            let set = if dir_only { &mut dirs } else { &mut any };
+            match pattern.strip_prefix('/') {
+                Some(anchored) => {
+                    set.add(glob(anchored)?);
+                }
+                None => {
+                    set.add(glob(pattern)?);
+                    set.add(glob(&format!("**/{}", pattern))?);
+                }
+            }
+        }
+        Ok(PatternSet {
+            any: any.build().map_err(invalid_pattern)?,
+            dirs: dirs.build().map_err(invalid_pattern)?,
+            len: patterns.len(),
+        })
+    }
+
+    fn is_match(&self, name: &Path, is_dir: bool) -> bool {
+        self.any.is_match(name) || (is_dir && self.dirs.is_match(name))
+    }
+}
+
+fn glob(pattern: &str) -> io::Result<globset::Glob> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(invalid_pattern)
+}
+
+fn invalid_pattern(e: globset::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+impl Filter {
+    pub fn new(include: &[&str], exclude: &[&str]) -> io::Result<Filter> {
+        Ok(Filter {
+            include: PatternSet::new(include)?,
+            exclude: PatternSet::new(exclude)?,
+        })
+    }
+
+    pub fn is_excluded(&self, name: &Path, is_dir: bool) -> bool {
+        self.exclude.is_match(name, is_dir)
+    }
+
+    fn is_included(&self, name: &Path, is_dir: bool) -> bool {
+        self.include.len == 0 || self.include.is_match(name, is_dir)
+    }
+}
+
+/// A file or directory to archive, with the name it will be stored under.
+pub struct Entry {
+    pub src: PathBuf,
+    pub name: PathBuf,
+}
+
+/// What [`collect`] found: the entries to archive, and how many directories
+/// could not be read and were left out with a warning.
+pub struct Walk {
+    pub entries: Vec<Entry>,
+    pub unreadable: usize,
+}
+
+/// The error to end a run with, once the archive is finished, when
+/// `unreadable` directories were left out of it: like GNU tar, carry on past
+/// them but exit with a failure status.
+pub fn check_unreadable(unreadable: usize) -> io::Result<()> {
+    match unreadable {
+        0 => Ok(()),
+        1 => Err(io::Error::other("1 directory could not be read and was left out")),
+        n => Err(io::Error::other(format!("{} directories could not be read and were left out", n))),
+    }
+}
+
+/// Expands `inputs` into the entries to archive, recursing into directories
+/// in sorted order. With include patterns, only matching files (or everything
+/// below a matching directory) are kept, plus the directories leading to them.
+///
+/// With `respect_ignore`, files ignored by `.gitignore`, `.ignore` or
+/// `.tartignore` at any level are skipped, along with `.git` itself. Ignore
+/// files in the parent directories up to the enclosing repository root and
+/// its `.git/info/exclude` apply too, so archiving a subdirectory of a
+/// repository keeps exactly the files git would consider.
+///
+/// Symlinked directories are only descended into with `dereference`. A
+/// directory that cannot be read is reported and left out, along with
+/// everything below it.
+pub fn collect(inputs: &[&str], filter: &Filter, respect_ignore: bool, dereference: bool) -> io::Result<Walk> {
+    let mut walker = Walker {
+        filter,
+        respect_ignore,
+        dereference,
+        ignores: Vec::new(),
+        open_dirs: Vec::new(),
+        entries: Vec::new(),
+        unreadable: 0,
+    };
+    for input in inputs {
+        let src = Path::new(input);
+        let metadata = match fs::symlink_metadata(src) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                eprintln!("⚠️ Skipping missing file: {}", input);
+                continue;
+            }
+        };
+        let abs = if respect_ignore {
+            let abs = fs::canonicalize(src)?;
+            let start = if metadata.is_dir() { abs.as_path() } else { abs.parent().unwrap_or(&abs) };
+            walker.ignores = parent_matchers(start, metadata.is_dir());
+            abs
+        } else {
+            src.to_path_buf()
+        };
+        walker.visit(src.to_path_buf(), archive_name(src), abs, false)?;
+    }
+    Ok(Walk {
+        entries: walker.entries,
+        unreadable: walker.unreadable,
+    })
+}
+
+/// The metadata of `path`, following a symlink only with `dereference`. A
+/// dangling symlink gives the link's own metadata either way.
+pub fn metadata(path: &Path, dereference: bool) -> io::Result<fs::Metadata> {
+    if dereference {
+        fs::metadata(path).or_else(|_| fs::symlink_metadata(path))
+    } else {
+        fs::symlink_metadata(path)
+    }
+}
+
+struct Walker<'a> {
+    filter: &'a Filter,
+    respect_ignore: bool,
+    dereference: bool,
+    ignores: Vec<Gitignore>,
+    // (device, inode) of the directories being walked, to stop symlink loops
+    // when dereferencing.
+    open_dirs: Vec<(u64, u64)>,
+    entries: Vec<Entry>,
+    unreadable: usize,
+}
+
+impl Walker<'_> {
+    fn visit(&mut self, src: PathBuf, name: PathBuf, abs: PathBuf, parent_included: bool) -> io::Result<()> {
+        // Without --dereference, symlinked directories are stored as entries
+        // but not followed.
+        let metadata = metadata(&src, self.dereference)?;
+        let is_dir = metadata.is_dir();
+        let is_root = name.as_os_str().is_empty();
+        if !is_root && self.filter.is_excluded(&name, is_dir) {
+            return Ok(());
+        }
+        if self.respect_ignore && !is_root && self.is_ignored(&abs, is_dir) {
+            return Ok(());
+        }
+        let included = parent_included || (!is_root && self.filter.is_included(&name, is_dir));
+
+        if !is_dir {
+            if included {
+                self.entries.push(Entry { src, name });
+            }
+            return Ok(());
+        }
+
+        let id = (metadata.dev(), metadata.ino());
+        if self.open_dirs.contains(&id) {
+            eprintln!("⚠️ Skipping {}: file system loop", src.display());
+            return Ok(());
+        }
+
+        let children = fs::read_dir(&src).and_then(|dir| {
+            dir.map(|entry| entry.map(|e| e.file_name()))
+                .collect::<io::Result<Vec<_>>>()
+        });
+        let mut children = match children {
+            Ok(children) => children,
+            Err(e) => {
+                eprintln!("⚠️ Skipping {}: {}", src.display(), e);
+                self.unreadable += 1;
+                return Ok(());
+            }
+        };
+        children.sort();
+
+        let matcher = if self.respect_ignore { dir_matcher(&abs) } else { None };
+        let pushed = matcher.map(|gi| self.ignores.push(gi)).is_some();
+
+        let dir_index = self.entries.len();
+        if !is_root {
+            self.entries.push(Entry { src: src.clone(), name: name.clone() });
+        }
+        self.open_dirs.push(id);
+        for child in children {
+            self.visit(src.join(&child), name.join(&child), abs.join(&child), included)?;
+        }
+        self.open_dirs.pop();
+        if !is_root && !included && self.entries.len() == dir_index + 1 {
+            self.entries.truncate(dir_index);
+        }
+
+        if pushed {
+            self.ignores.pop();
+        }
+        Ok(())
+    }
+
+    fn is_ignored(&self, abs: &Path, is_dir: bool) -> bool {
+        if abs.file_name().is_some_and(|n| n == ".git") {
+            return true;
+        }
+        for matcher in self.ignores.iter().rev() {
+            match matcher.matched(abs, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+// Builds the ignore stack that applies above `start`: `.git/info/exclude` and
+// the ignore files of every directory from the repository root down to
+// `start`'s parent. `start` itself is loaded by the walk when it is a
+// directory being archived. Outside a repository nothing above `start` applies.
+fn parent_matchers(start: &Path, start_is_walked: bool) -> Vec<Gitignore> {
+    let Some(root) = start.ancestors().find(|dir| dir.join(".git").exists()) else {
+        return Vec::new();
+    };
+
+    let mut matchers = Vec::new();
+    let exclude = root.join(".git/info/exclude");
+    if exclude.is_file() {
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(e) = builder.add(&exclude) {
+            eprintln!("⚠️ {}: {}", exclude.display(), e);
+        }
+        matchers.extend(builder.build().ok());
+    }
+
+    let mut dirs: Vec<&Path> = start.ancestors().take_while(|dir| *dir != root).collect();
+    dirs.push(root);
+    dirs.reverse();
+    if start_is_walked {
+        dirs.pop();
+    }
+    matchers.extend(dirs.into_iter().filter_map(dir_matcher));
+    matchers
+}
+
+fn dir_matcher(dir: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    for file in IGNORE_FILES {
+        let path = dir.join(file);
+        if path.is_file() {
+            if let Some(e) = builder.add(&path) {
+                eprintln!("⚠️ {}: {}", path.display(), e);
+            }
+        }
+    }
+    match builder.build() {
+        Ok(matcher) if !matcher.is_empty() => Some(matcher),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("⚠️ {}: {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+// Archive names are relative: leading `/`, `./` and `../` components are
+// dropped, as GNU tar does for member names.
+fn archive_name(path: &Path) -> PathBuf {
+    path.components()
+        .skip_while(|c| {
+            matches!(
+                c,
+                Component::RootDir | Component::Prefix(_) | Component::CurDir | Component::ParentDir
+            )
+        })
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn unreadable_directories_are_left_out_and_counted() {
+        assert!(check_unreadable(0).is_ok());
+        assert!(check_unreadable(1).unwrap_err().to_string().starts_with("1 directory "));
+        assert!(check_unreadable(2).unwrap_err().to_string().starts_with("2 directories "));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("locked/inner")).unwrap();
+        fs::write(src.join("file"), "data").unwrap();
+        fs::set_permissions(src.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+        let readable = fs::read_dir(src.join("locked")).is_ok();
+        let filter = Filter::new(&[], &[]).unwrap();
+        let walk = collect(&[src.to_str().unwrap()], &filter, false, false);
+        fs::set_permissions(src.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+        if readable {
+            return; // Root reads it anyway.
+        }
+
+        let walk = walk.unwrap();
+        let names: Vec<_> = walk.entries.iter().map(|e| e.src.strip_prefix(&src).unwrap()).collect();
+        assert_eq!(names, [Path::new(""), Path::new("file")]);
+        assert_eq!(walk.unreadable, 1);
+    }
+}
@@ -0,0 +1,268 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use rustix::fs::{self as rfs, XattrFlags};
+use rustix::io::Errno;
+
+use crate::owner::Owners;
+
+// The PAX keywords GNU tar, star and bsdtar use: xattrs as raw bytes under
+// `SCHILY.xattr.<name>`, ACLs in their text form under `SCHILY.acl.*`, and
+// the SELinux context under GNU tar's `RHT.security.selinux`.
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+const ACL_ACCESS: &str = "SCHILY.acl.access";
+const ACL_DEFAULT: &str = "SCHILY.acl.default";
+const SELINUX: &str = "RHT.security.selinux";
+
+const XATTR_ACL_ACCESS: &str = "system.posix_acl_access";
+const XATTR_ACL_DEFAULT: &str = "system.posix_acl_default";
+const XATTR_SELINUX: &str = "security.selinux";
+
+// Tags and layout of the Linux `system.posix_acl_*` xattr value: a version
+// word followed by (tag, perm, id) entries, all little-endian.
+const ACL_EA_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// Which kinds of archived attributes extraction restores: `--xattrs`,
+/// `--acls` and `--selinux`.
+#[derive(Clone, Copy, Default)]
+pub struct Restore {
+    pub xattrs: bool,
+    pub acls: bool,
+    pub selinux: bool,
+}
+
+/// An extended attribute ready to be set on an extracted file.
+pub struct Xattr {
+    name: String,
+    value: Vec<u8>,
+}
+
+/// Reads the `user.*`, `trusted.*` and `security.*` xattrs, POSIX ACLs and
+/// SELinux context of `path` as PAX records, following a symlink only when
+/// `follow` is set. ACL qualifiers are written star-style,
+/// `user:name:perms:id`, which GNU tar and bsdtar both read. File systems
+/// without xattr support yield nothing.
+pub fn read(path: &Path, owners: &Owners, follow: bool) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let list = |buf: &mut [u8]| {
+        if follow {
+            rfs::listxattr(path, buf)
+        } else {
+            rfs::llistxattr(path, buf)
+        }
+    };
+    let get = |name: &str, buf: &mut [u8]| {
+        if follow {
+            rfs::getxattr(path, name, buf)
+        } else {
+            rfs::lgetxattr(path, name, buf)
+        }
+    };
+    let names = match read_sized(list) {
+        Ok(names) => names,
+        Err(Errno::OPNOTSUPP) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let Ok(name) = std::str::from_utf8(name) else {
+            continue;
+        };
+        let value = match read_sized(|buf| get(name, buf)) {
+            Ok(value) => value,
+            // Removed since it was listed.
+            Err(Errno::NODATA) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match name {
+            XATTR_ACL_ACCESS | XATTR_ACL_DEFAULT => {
+                let key = if name == XATTR_ACL_ACCESS { ACL_ACCESS } else { ACL_DEFAULT };
+                match acl_to_text(&value, owners) {
+                    Some(text) => records.push((key.to_string(), text.into_bytes())),
+                    None => eprintln!("⚠️ Skipping malformed {} on {}", name, path.display()),
+                }
+            }
+            XATTR_SELINUX => {
+                let context = value.strip_suffix(&[0]).unwrap_or(&value);
+                records.push((SELINUX.to_string(), context.to_vec()));
+            }
+            _ if ["user.", "trusted.", "security."].iter().any(|ns| name.starts_with(ns)) => {
+                records.push((format!("{}{}", XATTR_PREFIX, name), value));
+            }
+            _ => {}
+        }
+    }
+    records.sort();
+    Ok(records)
+}
+
+// Runs a size-query-then-fetch xattr call, retrying if the value grew in
+// between.
+fn read_sized(mut call: impl FnMut(&mut [u8]) -> rustix::io::Result<usize>) -> rustix::io::Result<Vec<u8>> {
+    loop {
+        let size = call(&mut [])?;
+        let mut buf = vec![0; size];
+        match call(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            Err(Errno::RANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Picks the PAX records of an entry that `restore` asks for and converts
+/// them to xattrs. Unparseable ACLs are reported and skipped.
+pub fn from_pax(
+    records: &[(String, Vec<u8>)],
+    restore: Restore,
+    owners: &Owners,
+    numeric_owner: bool,
+    path: &Path,
+) -> Vec<Xattr> {
+    let mut xattrs = Vec::new();
+    for (key, value) in records {
+        let key = key.as_str();
+        let (name, value) = match key {
+            SELINUX if restore.selinux => {
+                let mut context = value.to_vec();
+                context.push(0);
+                (XATTR_SELINUX.to_string(), context)
+            }
+            ACL_ACCESS | ACL_DEFAULT if restore.acls => {
+                let name = if key == ACL_ACCESS { XATTR_ACL_ACCESS } else { XATTR_ACL_DEFAULT };
+                match std::str::from_utf8(value).ok().and_then(|text| acl_from_text(text, owners, numeric_owner)) {
+                    Some(acl) => (name.to_string(), acl),
+                    None => {
+                        eprintln!("⚠️ Skipping malformed {} on {}", key, path.display());
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                let Some(name) = key.strip_prefix(XATTR_PREFIX) else {
+                    continue;
+                };
+                let wanted = match name {
+                    XATTR_SELINUX => restore.selinux,
+                    XATTR_ACL_ACCESS | XATTR_ACL_DEFAULT => restore.acls,
+                    _ => restore.xattrs,
+                };
+                if !wanted {
+                    continue;
+                }
+                (name.to_string(), value.to_vec())
+            }
+        };
+        xattrs.push(Xattr { name, value });
+    }
+    xattrs
+}
+
+/// Sets `xattrs` on an extracted file or directory. As with GNU tar, an
+/// attribute that cannot be set (unsupported file system, missing privilege
+/// for `trusted.*`) is reported and the extraction goes on.
+pub fn apply(file: &File, path: &Path, xattrs: &[Xattr]) {
+    for xattr in xattrs {
+        if let Err(e) = rfs::fsetxattr(file, xattr.name.as_str(), &xattr.value, XattrFlags::empty()) {
+            eprintln!("⚠️ Cannot set {} on {}: {}", xattr.name, path.display(), io::Error::from(e));
+        }
+    }
+}
+
+fn acl_to_text(value: &[u8], owners: &Owners) -> Option<String> {
+    let (version, entries) = value.split_first_chunk::<4>()?;
+    if u32::from_le_bytes(*version) != ACL_EA_VERSION || entries.len() % 8 != 0 {
+        return None;
+    }
+    let mut text = Vec::new();
+    for entry in entries.chunks_exact(8) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u16::from_le_bytes([entry[2], entry[3]]);
+        let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+        let perms: String = [(4, 'r'), (2, 'w'), (1, 'x')]
+            .iter()
+            .map(|&(bit, c)| if perm & bit != 0 { c } else { '-' })
+            .collect();
+        text.push(match tag {
+            ACL_USER_OBJ => format!("user::{}", perms),
+            ACL_GROUP_OBJ => format!("group::{}", perms),
+            ACL_MASK => format!("mask::{}", perms),
+            ACL_OTHER => format!("other::{}", perms),
+            ACL_USER => {
+                let name = owners.user_name(id).unwrap_or_else(|| id.to_string());
+                format!("user:{}:{}:{}", name, perms, id)
+            }
+            ACL_GROUP => {
+                let name = owners.group_name(id).unwrap_or_else(|| id.to_string());
+                format!("group:{}:{}:{}", name, perms, id)
+            }
+            _ => return None,
+        });
+    }
+    Some(text.join(","))
+}
+
+// Parses the text form written by `acl_to_text`, GNU tar or star: entries
+// separated by commas or newlines, `#` comments, short tags and an optional
+// trailing numeric id. A qualifier resolves by name first (unless
+// --numeric-owner), then through the trailing id, then as a number.
+fn acl_from_text(text: &str, owners: &Owners, numeric_owner: bool) -> Option<Vec<u8>> {
+    let mut entries = Vec::new();
+    for entry in text.split([',', '\n']) {
+        let entry = entry.split('#').next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = entry.split(':').collect();
+        let (tag, qualifier, perms) = match fields.as_slice() {
+            [tag, qualifier, perms] | [tag, qualifier, perms, _] => (*tag, *qualifier, *perms),
+            _ => return None,
+        };
+        let trailing_id = fields.get(3).and_then(|id| id.parse::<u32>().ok());
+        let (tag, id) = match (tag, qualifier.is_empty()) {
+            ("user" | "u", true) => (ACL_USER_OBJ, ACL_UNDEFINED_ID),
+            ("group" | "g", true) => (ACL_GROUP_OBJ, ACL_UNDEFINED_ID),
+            ("mask" | "m", _) => (ACL_MASK, ACL_UNDEFINED_ID),
+            ("other" | "o", _) => (ACL_OTHER, ACL_UNDEFINED_ID),
+            ("user" | "u", false) => {
+                let by_name = if numeric_owner { None } else { owners.uid(qualifier) };
+                (ACL_USER, by_name.or(trailing_id).or_else(|| qualifier.parse().ok())?)
+            }
+            ("group" | "g", false) => {
+                let by_name = if numeric_owner { None } else { owners.gid(qualifier) };
+                (ACL_GROUP, by_name.or(trailing_id).or_else(|| qualifier.parse().ok())?)
+            }
+            _ => return None,
+        };
+        let mut perm = 0u16;
+        for c in perms.chars() {
+            perm |= match c {
+                'r' => 4,
+                'w' => 2,
+                'x' => 1,
+                '-' => 0,
+                _ => return None,
+            };
+        }
+        entries.push((tag, id, perm));
+    }
+    // The kernel wants entries ordered by tag, then by id.
+    entries.sort();
+    let mut value = ACL_EA_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Some(value)
+}